@@ -0,0 +1,591 @@
+use std::collections::VecDeque;
+use std::ffi::OsString;
+use std::fs;
+use std::io;
+use std::os::unix::fs::{PermissionsExt, symlink};
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, Stdio};
+
+use anyhow::{Context, Result};
+
+use crate::utils::path_to_str;
+
+// Symlink chains longer than this are treated as loops, like the kernel does.
+const MAX_SYMLINK_HOPS: usize = 40;
+
+/// Extracts an archive into `dest`, dropping the first `strip_components`
+/// directories of every entry. Entries that would end up outside of `dest`,
+/// either directly or through a symlink, make the whole extraction fail
+/// before anything is written to `dest`.
+pub fn extract_archive(
+    path: &Path,
+    archive_type: &str,
+    dest: &Path,
+    strip_components: usize,
+) -> Result<()> {
+    // Unpacking next to the destination keeps the final moves on one filesystem.
+    let staging_parent = dest.parent().unwrap_or(dest);
+    fs::create_dir_all(staging_parent)?;
+    let staging = tempfile::Builder::new()
+        .prefix(".extract-")
+        .tempdir_in(staging_parent)?;
+    let file = fs::File::open(path)?;
+
+    match archive_type {
+        "tar" => unpack_tar(file, staging.path())?,
+        "tar-gzip" => unpack_tar(flate2::read::GzDecoder::new(file), staging.path())?,
+        "tar-bzip2" => unpack_tar(bzip2::read::BzDecoder::new(file), staging.path())?,
+        "tar-xz" => unpack_tar(xz2::read::XzDecoder::new(file), staging.path())?,
+        "tar-lzma" => {
+            let stream = xz2::stream::Stream::new_lzma_decoder(u64::MAX)?;
+            unpack_tar(
+                xz2::read::XzDecoder::new_stream(file, stream),
+                staging.path(),
+            )?;
+        }
+        "tar-lzip" => unpack_tar(
+            lzma_rust2::LzipReader::new(io::BufReader::new(file)),
+            staging.path(),
+        )?,
+        "tar-zst" => unpack_tar(zstd::stream::read::Decoder::new(file)?, staging.path())?,
+        "zip" => unpack_zip(file, staging.path())?,
+        // Like flatpak-builder, these are left to the usual command line tools.
+        "rpm" => run_extractor_pipeline(
+            ("rpm2cpio", &[path_to_str(path)?]),
+            (
+                "cpio",
+                &["-i", "-d", "-m", "--quiet", "--no-absolute-filenames"],
+            ),
+            staging.path(),
+        )?,
+        "7z" => run_extractor(
+            "7z",
+            &["x", "-y", "-bd", path_to_str(path)?],
+            staging.path(),
+        )?,
+        other => {
+            return Err(anyhow::anyhow!("Unsupported archive type: {other}"));
+        }
+    }
+
+    // Only directories at the stripped depth contribute their contents.
+    let mut roots = Vec::new();
+    for entry in walkdir::WalkDir::new(staging.path())
+        .min_depth(strip_components)
+        .max_depth(strip_components)
+    {
+        let entry = entry?;
+        if entry.file_type().is_dir() {
+            roots.push(entry.into_path());
+        }
+    }
+    for root in &roots {
+        check_symlinks(root)?;
+    }
+    fs::create_dir_all(dest)?;
+    for root in &roots {
+        merge_into(root, dest)?;
+    }
+    Ok(())
+}
+
+fn run_extractor(command: &str, args: &[&str], dir: &Path) -> Result<()> {
+    let status = Command::new(command)
+        .args(args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .status()
+        .with_context(|| format!("Failed to run {command}"))?;
+    if !status.success() {
+        anyhow::bail!("{command} failed to extract the archive");
+    }
+    Ok(())
+}
+
+// Runs `producer | consumer` in `dir`, failing when either of them fails.
+fn run_extractor_pipeline(
+    (producer, producer_args): (&str, &[&str]),
+    (consumer, consumer_args): (&str, &[&str]),
+    dir: &Path,
+) -> Result<()> {
+    let mut producer_process = Command::new(producer)
+        .args(producer_args)
+        .current_dir(dir)
+        .stdin(Stdio::null())
+        .stdout(Stdio::piped())
+        .spawn()
+        .with_context(|| format!("Failed to run {producer}"))?;
+    let output = producer_process
+        .stdout
+        .take()
+        .with_context(|| format!("Failed to read the output of {producer}"))?;
+    let consumer_status = Command::new(consumer)
+        .args(consumer_args)
+        .current_dir(dir)
+        .stdin(output)
+        .status();
+    // The producer is waited for either way, the consumer closing the pipe
+    // makes it exit.
+    let producer_status = producer_process.wait()?;
+    let consumer_status = consumer_status.with_context(|| format!("Failed to run {consumer}"))?;
+    if !producer_status.success() {
+        anyhow::bail!("{producer} failed to extract the archive");
+    }
+    if !consumer_status.success() {
+        anyhow::bail!("{consumer} failed to extract the archive");
+    }
+    Ok(())
+}
+
+// Turns an archive entry name into a path relative to the extraction root,
+// refusing absolute paths and parent directory references.
+fn entry_path(name: &Path) -> Result<PathBuf> {
+    let mut path = PathBuf::new();
+    for component in name.components() {
+        match component {
+            Component::Normal(part) => path.push(part),
+            Component::CurDir => {}
+            Component::ParentDir | Component::RootDir | Component::Prefix(_) => {
+                anyhow::bail!(
+                    "Archive entry {} points outside of the extraction directory",
+                    name.display()
+                );
+            }
+        }
+    }
+    Ok(path)
+}
+
+// Prepares `root/relative` for writing, making sure that none of its parents
+// is a symlink so that entries can never be written through one.
+fn prepare_entry(root: &Path, relative: &Path) -> Result<PathBuf> {
+    let mut current = root.to_path_buf();
+    if let Some(parent) = relative.parent() {
+        for part in parent.components() {
+            current.push(part);
+            match fs::symlink_metadata(&current) {
+                Ok(metadata) if metadata.file_type().is_symlink() => {
+                    anyhow::bail!("Archive entry {} is inside a symlink", relative.display());
+                }
+                Ok(metadata) if metadata.is_dir() => {}
+                Ok(_) => fs::remove_file(&current)?,
+                Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+                Err(error) => return Err(error.into()),
+            }
+            if !current.exists() {
+                fs::create_dir(&current)?;
+            }
+        }
+    }
+
+    let target = root.join(relative);
+    match fs::symlink_metadata(&target) {
+        Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&target)?,
+        Ok(_) => fs::remove_file(&target)?,
+        Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+        Err(error) => return Err(error.into()),
+    }
+    Ok(target)
+}
+
+fn has_symlink_parent(root: &Path, relative: &Path) -> bool {
+    relative.ancestors().skip(1).any(|parent| {
+        !parent.as_os_str().is_empty()
+            && fs::symlink_metadata(root.join(parent)).is_ok_and(|m| m.file_type().is_symlink())
+    })
+}
+
+// Directories always stay writable by the owner, otherwise neither the rest
+// of the archive nor later cleanups could write to them.
+fn create_entry_dir(root: &Path, relative: &Path, mode: u32) -> Result<()> {
+    let path = root.join(relative);
+    if !fs::symlink_metadata(&path).is_ok_and(|metadata| metadata.is_dir()) {
+        let path = prepare_entry(root, relative)?;
+        fs::create_dir(&path)?;
+    }
+    fs::set_permissions(&path, fs::Permissions::from_mode((mode & 0o777) | 0o700))?;
+    Ok(())
+}
+
+fn unpack_tar(reader: impl io::Read, root: &Path) -> Result<()> {
+    let mut archive = tar::Archive::new(reader);
+    for entry in archive.entries()? {
+        let mut entry = entry?;
+        let relative = entry_path(&entry.path()?)?;
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        let mode = entry.header().mode()?;
+        match entry.header().entry_type() {
+            tar::EntryType::Directory => create_entry_dir(root, &relative, mode)?,
+            tar::EntryType::Regular | tar::EntryType::Continuous | tar::EntryType::Symlink => {
+                // Unpacking a single entry keeps its mode, mtime and link target.
+                let target = prepare_entry(root, &relative)?;
+                entry
+                    .unpack(&target)
+                    .with_context(|| format!("Failed to extract {}", relative.display()))?;
+            }
+            tar::EntryType::Link => {
+                let link_name = entry.link_name()?.ok_or_else(|| {
+                    anyhow::anyhow!("Hard link {} has no target", relative.display())
+                })?;
+                let source_relative = entry_path(&link_name)?;
+                let source = root.join(&source_relative);
+                let source_is_file = fs::symlink_metadata(&source)
+                    .is_ok_and(|metadata| metadata.file_type().is_file());
+                if !source_is_file || has_symlink_parent(root, &source_relative) {
+                    anyhow::bail!(
+                        "Hard link {} does not point to a file in the archive",
+                        relative.display()
+                    );
+                }
+                let target = prepare_entry(root, &relative)?;
+                fs::hard_link(&source, &target)
+                    .or_else(|_| fs::copy(&source, &target).map(drop))?;
+            }
+            // Devices, FIFOs and global headers have no place in a source tree.
+            _ => {}
+        }
+    }
+    Ok(())
+}
+
+fn unpack_zip(file: fs::File, root: &Path) -> Result<()> {
+    let mut archive = zip::ZipArchive::new(file)?;
+    for index in 0..archive.len() {
+        let mut entry = archive.by_index(index)?;
+        let relative = entry_path(Path::new(entry.name()))?;
+        if relative.as_os_str().is_empty() {
+            continue;
+        }
+        if entry.is_dir() {
+            create_entry_dir(root, &relative, entry.unix_mode().unwrap_or(0o755))?;
+        } else if entry.is_symlink() {
+            let mut link_target = String::new();
+            io::Read::read_to_string(&mut entry, &mut link_target)?;
+            let target = prepare_entry(root, &relative)?;
+            symlink(link_target, &target)?;
+        } else {
+            let target = prepare_entry(root, &relative)?;
+            let mut output = fs::File::create(&target)?;
+            io::copy(&mut entry, &mut output)?;
+            let mode = entry.unix_mode().unwrap_or(0o644) & 0o777;
+            fs::set_permissions(&target, fs::Permissions::from_mode(mode))?;
+        }
+    }
+    Ok(())
+}
+
+// Checks that every symlink under `root` resolves to somewhere inside it,
+// following chains of symlinks the same way path resolution would.
+fn check_symlinks(root: &Path) -> Result<()> {
+    for entry in walkdir::WalkDir::new(root).min_depth(1) {
+        let entry = entry?;
+        if entry.path_is_symlink() {
+            let relative = entry.path().strip_prefix(root)?;
+            if !resolves_inside(root, relative)? {
+                anyhow::bail!(
+                    "Archive symlink {} points outside of the extraction directory",
+                    relative.display()
+                );
+            }
+        }
+    }
+    Ok(())
+}
+
+fn resolves_inside(root: &Path, link: &Path) -> Result<bool> {
+    let mut resolved: Vec<OsString> = link
+        .parent()
+        .into_iter()
+        .flat_map(Path::components)
+        .map(|component| component.as_os_str().to_os_string())
+        .collect();
+    let target = fs::read_link(root.join(link))?;
+    if target.is_absolute() {
+        return Ok(false);
+    }
+    let mut pending: VecDeque<OsString> = target
+        .components()
+        .map(|component| component.as_os_str().to_os_string())
+        .collect();
+
+    let mut hops = 0;
+    while let Some(part) = pending.pop_front() {
+        match Path::new(&part).components().next() {
+            Some(Component::Normal(name)) => {
+                resolved.push(name.to_os_string());
+                let current: PathBuf = root.join(resolved.iter().collect::<PathBuf>());
+                if fs::symlink_metadata(&current).is_ok_and(|m| m.file_type().is_symlink()) {
+                    hops += 1;
+                    let target = fs::read_link(&current)?;
+                    if hops > MAX_SYMLINK_HOPS || target.is_absolute() {
+                        return Ok(false);
+                    }
+                    resolved.pop();
+                    for component in target.components().rev() {
+                        pending.push_front(component.as_os_str().to_os_string());
+                    }
+                }
+            }
+            Some(Component::ParentDir) => {
+                if resolved.pop().is_none() {
+                    return Ok(false);
+                }
+            }
+            Some(Component::CurDir) | None => {}
+            Some(Component::RootDir | Component::Prefix(_)) => return Ok(false),
+        }
+    }
+    Ok(true)
+}
+
+// Moves the contents of `source` into `dest`, merging with directories that
+// already exist there.
+fn merge_into(source: &Path, dest: &Path) -> Result<()> {
+    for entry in fs::read_dir(source)? {
+        let entry = entry?;
+        let source_path = entry.path();
+        let dest_path = dest.join(entry.file_name());
+        let is_dir = entry.file_type()?.is_dir();
+        match fs::symlink_metadata(&dest_path) {
+            Ok(metadata) if is_dir && metadata.is_dir() => {
+                merge_into(&source_path, &dest_path)?;
+                fs::set_permissions(&dest_path, fs::metadata(&source_path)?.permissions())?;
+                continue;
+            }
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(&dest_path)?,
+            Ok(_) => fs::remove_file(&dest_path)?,
+            Err(error) if error.kind() == io::ErrorKind::NotFound => {}
+            Err(error) => return Err(error.into()),
+        }
+        move_path(&source_path, &dest_path)?;
+    }
+    Ok(())
+}
+
+fn move_path(source: &Path, dest: &Path) -> Result<()> {
+    match fs::rename(source, dest) {
+        Ok(()) => Ok(()),
+        Err(error) if error.raw_os_error() == Some(nix::errno::Errno::EXDEV as i32) => {
+            copy_tree(source, dest)?;
+            if fs::symlink_metadata(source)?.is_dir() {
+                fs::remove_dir_all(source)?;
+            } else {
+                fs::remove_file(source)?;
+            }
+            Ok(())
+        }
+        Err(error) => Err(error).with_context(|| format!("Failed to move {}", source.display())),
+    }
+}
+
+// Copies a tree across filesystems, keeping symlinks, modes and file mtimes.
+fn copy_tree(source: &Path, dest: &Path) -> Result<()> {
+    let metadata = fs::symlink_metadata(source)?;
+    if metadata.file_type().is_symlink() {
+        symlink(fs::read_link(source)?, dest)?;
+    } else if metadata.is_dir() {
+        fs::create_dir(dest)?;
+        for entry in fs::read_dir(source)? {
+            let entry = entry?;
+            copy_tree(&entry.path(), &dest.join(entry.file_name()))?;
+        }
+        fs::set_permissions(dest, metadata.permissions())?;
+    } else {
+        fs::copy(source, dest)?;
+        filetime::set_file_mtime(
+            dest,
+            filetime::FileTime::from_last_modification_time(&metadata),
+        )?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::io::Write as _;
+
+    fn tar_with_file(name: &str, contents: &[u8]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        let mut header = tar::Header::new_gnu();
+        header.set_size(contents.len() as u64);
+        header.set_mode(0o644);
+        header.set_cksum();
+        builder.append_data(&mut header, name, contents).unwrap();
+        builder.into_inner().unwrap()
+    }
+
+    // Builds a tar archive from raw headers, since tar::Builder refuses to
+    // write the hostile names these tests need.
+    fn crafted_tar(entries: &[(&str, tar::EntryType, &str, u32)]) -> Vec<u8> {
+        let mut builder = tar::Builder::new(Vec::new());
+        for (name, entry_type, contents_or_link, mode) in entries {
+            let mut header = tar::Header::new_old();
+            let name_field = &mut header.as_old_mut().name;
+            name_field[..name.len()].copy_from_slice(name.as_bytes());
+            header.set_entry_type(*entry_type);
+            header.set_mode(*mode);
+            let contents: &[u8] = if entry_type.is_symlink() {
+                let link_field = &mut header.as_old_mut().linkname;
+                link_field[..contents_or_link.len()].copy_from_slice(contents_or_link.as_bytes());
+                &[]
+            } else {
+                contents_or_link.as_bytes()
+            };
+            header.set_size(contents.len() as u64);
+            header.set_cksum();
+            builder.append(&header, contents).unwrap();
+        }
+        builder.into_inner().unwrap()
+    }
+
+    fn extract_bytes(data: &[u8], dest: &Path, strip_components: usize) -> Result<()> {
+        let archive = tempfile::NamedTempFile::new().unwrap();
+        fs::write(archive.path(), data).unwrap();
+        extract_archive(archive.path(), "tar", dest, strip_components)
+    }
+
+    #[test]
+    fn test_extract_compressed_tarballs() {
+        let tarball = tar_with_file("pkg-1.0/README", b"hello");
+        let bzip2 = {
+            let mut encoder =
+                bzip2::write::BzEncoder::new(Vec::new(), bzip2::Compression::default());
+            encoder.write_all(&tarball).unwrap();
+            encoder.finish().unwrap()
+        };
+        let zstd = zstd::stream::encode_all(tarball.as_slice(), 0).unwrap();
+        let lzip = {
+            let mut encoder =
+                lzma_rust2::LzipWriter::new(Vec::new(), lzma_rust2::LzipOptions::default());
+            encoder.write_all(&tarball).unwrap();
+            encoder.finish().unwrap()
+        };
+
+        let dir = tempfile::tempdir().unwrap();
+        for (archive_type, data) in [("tar-bzip2", bzip2), ("tar-zst", zstd), ("tar-lzip", lzip)] {
+            let archive_path = dir.path().join(archive_type);
+            fs::write(&archive_path, data).unwrap();
+            let extract_dir = dir.path().join(format!("{archive_type}-extracted"));
+            extract_archive(&archive_path, archive_type, &extract_dir, 1).unwrap();
+            assert_eq!(
+                fs::read_to_string(extract_dir.join("README")).unwrap(),
+                "hello"
+            );
+        }
+        assert!(extract_archive(&dir.path().join("tar-zst"), "tar-lzop", dir.path(), 1).is_err());
+    }
+
+    #[test]
+    fn test_extract_preserves_modes_and_symlinks() {
+        let data = crafted_tar(&[
+            ("pkg/", tar::EntryType::Directory, "", 0o555),
+            ("pkg/bin/", tar::EntryType::Directory, "", 0o755),
+            ("pkg/bin/run", tar::EntryType::Regular, "#!/bin/sh\n", 0o755),
+            ("pkg/data", tar::EntryType::Regular, "data", 0o444),
+            ("pkg/tools", tar::EntryType::Symlink, "bin", 0o777),
+            (
+                "pkg/bin/self",
+                tar::EntryType::Symlink,
+                "../tools/run",
+                0o777,
+            ),
+        ]);
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("dest");
+        extract_bytes(&data, &dest, 1).unwrap();
+
+        let mode = |path: &str| fs::metadata(dest.join(path)).unwrap().permissions().mode() & 0o777;
+        assert_eq!(mode("bin/run"), 0o755);
+        assert_eq!(mode("data"), 0o444);
+        assert_eq!(fs::read_link(dest.join("tools")).unwrap(), Path::new("bin"));
+        assert_eq!(
+            fs::read_to_string(dest.join("bin/self")).unwrap(),
+            "#!/bin/sh\n"
+        );
+        // Nothing is left behind next to the destination.
+        assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_extract_rejects_escaping_entries() {
+        let hostile = [
+            vec![("pkg/../../evil", tar::EntryType::Regular, "evil", 0o644)],
+            vec![("/tmp/evil", tar::EntryType::Regular, "evil", 0o644)],
+            vec![("pkg/link", tar::EntryType::Symlink, "/etc", 0o777)],
+            vec![("pkg/link", tar::EntryType::Symlink, "../..", 0o777)],
+            vec![
+                ("pkg/here", tar::EntryType::Symlink, ".", 0o777),
+                ("pkg/link", tar::EntryType::Symlink, "here/..", 0o777),
+            ],
+            vec![
+                ("pkg/dir", tar::EntryType::Symlink, "sub", 0o777),
+                ("pkg/dir/evil", tar::EntryType::Regular, "evil", 0o644),
+            ],
+        ];
+        for entries in hostile {
+            let dir = tempfile::tempdir().unwrap();
+            let dest = dir.path().join("dest");
+            let result = extract_bytes(&crafted_tar(&entries), &dest, 1);
+            assert!(result.is_err(), "{entries:?} was extracted");
+            assert!(!dest.exists());
+            assert_eq!(fs::read_dir(dir.path()).unwrap().count(), 0);
+        }
+    }
+
+    #[test]
+    fn test_extractor_pipeline_checks_both_commands() {
+        let dir = tempfile::tempdir().unwrap();
+        run_extractor_pipeline(
+            ("echo", &["content"]),
+            ("sh", &["-c", "cat > out"]),
+            dir.path(),
+        )
+        .unwrap();
+        assert_eq!(
+            fs::read_to_string(dir.path().join("out")).unwrap(),
+            "content\n"
+        );
+
+        let error = run_extractor_pipeline(("false", &[]), ("cat", &[]), dir.path()).unwrap_err();
+        assert!(error.to_string().starts_with("false failed"));
+        let error = run_extractor_pipeline(
+            ("echo", &["content"]),
+            ("sh", &["-c", "cat > /dev/null; exit 1"]),
+            dir.path(),
+        )
+        .unwrap_err();
+        assert!(error.to_string().starts_with("sh failed"));
+        assert!(
+            run_extractor_pipeline(("echo", &[]), ("missing-extractor", &[]), dir.path()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_copy_tree() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source");
+        fs::create_dir_all(source.join("sub")).unwrap();
+        fs::write(source.join("sub/run"), "run").unwrap();
+        fs::set_permissions(source.join("sub/run"), fs::Permissions::from_mode(0o750)).unwrap();
+        symlink("sub/run", source.join("link")).unwrap();
+
+        let dest = dir.path().join("dest");
+        copy_tree(&source, &dest).unwrap();
+        let run = fs::metadata(dest.join("sub/run")).unwrap();
+        assert_eq!(run.permissions().mode() & 0o777, 0o750);
+        assert_eq!(
+            run.modified().unwrap(),
+            fs::metadata(source.join("sub/run"))
+                .unwrap()
+                .modified()
+                .unwrap()
+        );
+        assert_eq!(
+            fs::read_link(dest.join("link")).unwrap(),
+            Path::new("sub/run")
+        );
+    }
+}
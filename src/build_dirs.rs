@@ -19,6 +19,22 @@ impl BuildDirs {
     pub fn build_system_dir(&self) -> PathBuf {
         self.build_dir().join("_build")
     }
+    pub fn cargo_target_dir(&self) -> PathBuf {
+        self.build_dir().join("cargo-target")
+    }
+    pub fn module_build_system_dir(&self, module: &str) -> PathBuf {
+        self.build_dir().join(format!("_build-{module}"))
+    }
+    pub fn profile_build_system_dir(&self, profile: &str, module: Option<&str>) -> PathBuf {
+        let profile_dir = self.build_dir().join("profiles").join(profile);
+        match module {
+            Some(module) => profile_dir.join(format!("_build-{module}")),
+            None => profile_dir.join("_build"),
+        }
+    }
+    pub fn module_cargo_target_dir(&self, module: &str) -> PathBuf {
+        self.build_dir().join(format!("cargo-target-{module}"))
+    }
     pub fn flatpak_builder_dir(&self) -> PathBuf {
         self.build_dir().join("flatpak-builder")
     }
@@ -51,6 +67,23 @@ mod tests {
         assert_eq!(dirs.build_dir(), base.join(".flatplay"));
         assert_eq!(dirs.repo_dir(), base.join(".flatplay/repo"));
         assert_eq!(dirs.build_system_dir(), base.join(".flatplay/_build"));
+        assert_eq!(dirs.cargo_target_dir(), base.join(".flatplay/cargo-target"));
+        assert_eq!(
+            dirs.module_build_system_dir("libfoo"),
+            base.join(".flatplay/_build-libfoo")
+        );
+        assert_eq!(
+            dirs.profile_build_system_dir("asan", None),
+            base.join(".flatplay/profiles/asan/_build")
+        );
+        assert_eq!(
+            dirs.profile_build_system_dir("asan", Some("libfoo")),
+            base.join(".flatplay/profiles/asan/_build-libfoo")
+        );
+        assert_eq!(
+            dirs.module_cargo_target_dir("libfoo"),
+            base.join(".flatplay/cargo-target-libfoo")
+        );
         assert_eq!(
             dirs.flatpak_builder_dir(),
             base.join(".flatplay/flatpak-builder")
@@ -0,0 +1,162 @@
+use std::io::{self, Read, Write};
+
+use anyhow::Result;
+use sha2::Digest;
+
+/// Checksum algorithms that sources can be verified with, weakest first.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Algorithm {
+    Md5,
+    Sha1,
+    Sha256,
+    Sha512,
+}
+
+impl Algorithm {
+    /// Strongest first, so the first one a source specifies is the one to use.
+    pub const PREFERENCE: [Self; 4] = [Self::Sha512, Self::Sha256, Self::Sha1, Self::Md5];
+
+    /// The name used for the algorithm in manifests.
+    pub const fn name(self) -> &'static str {
+        match self {
+            Self::Md5 => "md5",
+            Self::Sha1 => "sha1",
+            Self::Sha256 => "sha256",
+            Self::Sha512 => "sha512",
+        }
+    }
+
+    const fn hex_len(self) -> usize {
+        match self {
+            Self::Md5 => 32,
+            Self::Sha1 => 40,
+            Self::Sha256 => 64,
+            Self::Sha512 => 128,
+        }
+    }
+}
+
+/// An expected checksum, with the digest in lowercase hex.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Checksum {
+    pub algorithm: Algorithm,
+    pub hex: String,
+}
+
+impl Checksum {
+    pub fn new(algorithm: Algorithm, hex: &str) -> Result<Self> {
+        if hex.len() != algorithm.hex_len() || !hex.bytes().all(|b| b.is_ascii_hexdigit()) {
+            anyhow::bail!("Invalid {} checksum: {hex}", algorithm.name());
+        }
+        Ok(Self {
+            algorithm,
+            hex: hex.to_ascii_lowercase(),
+        })
+    }
+
+    /// Fails with a description of the mismatch unless `actual` is this
+    /// checksum. `what` names the file that was hashed.
+    pub fn check(&self, actual: &str, what: impl std::fmt::Display) -> Result<()> {
+        if actual != self.hex {
+            anyhow::bail!(
+                "{} mismatch for {what}: expected {}, got {actual}",
+                self.algorithm.name().to_uppercase(),
+                self.hex
+            );
+        }
+        Ok(())
+    }
+
+    pub fn hasher(&self) -> Hasher {
+        match self.algorithm {
+            Algorithm::Md5 => Hasher::Md5(md5::Md5::new()),
+            Algorithm::Sha1 => Hasher::Sha1(sha1::Sha1::new()),
+            Algorithm::Sha256 => Hasher::Sha256(sha2::Sha256::new()),
+            Algorithm::Sha512 => Hasher::Sha512(sha2::Sha512::new()),
+        }
+    }
+}
+
+/// Incremental hashing with any of the supported algorithms.
+pub enum Hasher {
+    Md5(md5::Md5),
+    Sha1(sha1::Sha1),
+    Sha256(sha2::Sha256),
+    Sha512(sha2::Sha512),
+}
+
+impl Hasher {
+    pub fn update(&mut self, data: &[u8]) {
+        match self {
+            Self::Md5(hasher) => hasher.update(data),
+            Self::Sha1(hasher) => hasher.update(data),
+            Self::Sha256(hasher) => hasher.update(data),
+            Self::Sha512(hasher) => hasher.update(data),
+        }
+    }
+
+    /// Hashes everything read from `reader` while writing it to `writer`.
+    pub fn copy(&mut self, reader: &mut impl Read, writer: &mut impl Write) -> io::Result<u64> {
+        let mut buffer = vec![0; 64 * 1024];
+        let mut total = 0;
+        loop {
+            let read = match reader.read(&mut buffer) {
+                Ok(0) => return Ok(total),
+                Ok(read) => read,
+                Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+                Err(error) => return Err(error),
+            };
+            self.update(&buffer[..read]);
+            writer.write_all(&buffer[..read])?;
+            total += read as u64;
+        }
+    }
+
+    pub fn finalize_hex(self) -> String {
+        let digest = match self {
+            Self::Md5(hasher) => hasher.finalize().to_vec(),
+            Self::Sha1(hasher) => hasher.finalize().to_vec(),
+            Self::Sha256(hasher) => hasher.finalize().to_vec(),
+            Self::Sha512(hasher) => hasher.finalize().to_vec(),
+        };
+        digest.iter().map(|byte| format!("{byte:02x}")).collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_hashers() {
+        let cases = [
+            (Algorithm::Md5, "5eb63bbbe01eeed093cb22bb8f5acdc3"),
+            (Algorithm::Sha1, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed"),
+            (
+                Algorithm::Sha256,
+                "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+            ),
+            (
+                Algorithm::Sha512,
+                "309ecc489c12d6eb4cc40f50c902f2b4d0ed77ee511a7c7a9bcd3ca86d4cd86f\
+                 989dd35bc5ff499670da34255b45b0cfd830e81f605dcf7dc5542e93ae9cd76f",
+            ),
+        ];
+        for (algorithm, hex) in cases {
+            let checksum = Checksum::new(algorithm, &hex.to_ascii_uppercase()).unwrap();
+            assert_eq!(checksum.hex, hex);
+            let mut hasher = checksum.hasher();
+            hasher.update(b"hello ");
+            hasher.update(b"world");
+            assert_eq!(hasher.finalize_hex(), hex);
+        }
+    }
+
+    #[test]
+    fn test_invalid_checksums() {
+        assert!(Checksum::new(Algorithm::Sha256, "abc").is_err());
+        assert!(Checksum::new(Algorithm::Md5, &"g".repeat(32)).is_err());
+        assert!(Checksum::new(Algorithm::Sha1, &"0".repeat(64)).is_err());
+        assert!(Checksum::new(Algorithm::Sha512, &"0".repeat(128)).is_ok());
+    }
+}
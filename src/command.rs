@@ -1,8 +1,9 @@
+use std::os::unix::process::CommandExt;
 use std::path::Path;
-use std::process::{Command, Stdio};
+use std::process::{Child, Command, Output, Stdio};
 
 use crate::utils::{command_header, verbose};
-use anyhow::Result;
+use anyhow::{Context, Result};
 
 #[derive(Debug)]
 pub struct InterruptedError;
@@ -37,24 +38,13 @@ fn command_succeeds(cmd: &str, args: &[&str]) -> bool {
         .is_ok_and(|s| s.success())
 }
 
-// Runs a command, handling Flatpak sandbox and container specifics.
-pub fn run_command(command: &str, args: &[&str], working_dir: Option<&Path>) -> Result<()> {
-    let mut command_args = args.to_vec();
-
-    // Workaround for rofiles-fuse issues in containers.
-    if command == "flatpak-builder"
-        && is_inside_container()
-        && !command_args.contains(&"--disable-rofiles-fuse")
-    {
-        verbose("Detected container, adding --disable-rofiles-fuse");
-        command_args.push("--disable-rofiles-fuse");
-    }
-
-    let (program, final_args) = if is_sandboxed() {
+// Wraps a command so that it runs on the host when flatplay itself is sandboxed.
+fn host_command<'a>(command: &'a str, args: &[&'a str]) -> (&'a str, Vec<&'a str>) {
+    if is_sandboxed() {
         if command_succeeds("host-spawn", &["--version"]) {
             verbose("Detected Flatpak sandbox, using host-spawn");
             let mut new_args = vec![command];
-            new_args.extend_from_slice(&command_args);
+            new_args.extend_from_slice(args);
             ("host-spawn", new_args)
         } else {
             verbose("Detected Flatpak sandbox, using flatpak-spawn");
@@ -64,12 +54,39 @@ pub fn run_command(command: &str, args: &[&str], working_dir: Option<&Path>) ->
                 "--env=TERM=xterm-256color",
                 command,
             ];
-            new_args.extend_from_slice(&command_args);
+            new_args.extend_from_slice(args);
             ("flatpak-spawn", new_args)
         }
     } else {
-        (command, command_args)
-    };
+        (command, args.to_vec())
+    }
+}
+
+// Runs a command quietly and captures its output, for queries rather than build steps.
+pub fn command_output(command: &str, args: &[&str]) -> Result<Output> {
+    let (program, final_args) = host_command(command, args);
+    verbose(format!("Querying: {program} {}", final_args.join(" ")));
+    Command::new(program)
+        .args(&final_args)
+        .stdin(Stdio::null())
+        .output()
+        .with_context(|| format!("Failed to run {command}"))
+}
+
+// Runs a command, handling Flatpak sandbox and container specifics.
+pub fn run_command(command: &str, args: &[&str], working_dir: Option<&Path>) -> Result<()> {
+    let mut command_args = args.to_vec();
+
+    // Workaround for rofiles-fuse issues in containers.
+    if command == "flatpak-builder"
+        && is_inside_container()
+        && !command_args.contains(&"--disable-rofiles-fuse")
+    {
+        verbose("Detected container, adding --disable-rofiles-fuse");
+        command_args.push("--disable-rofiles-fuse");
+    }
+
+    let (program, final_args) = host_command(command, &command_args);
 
     command_header(program, &final_args);
     let mut cmd = Command::new(program);
@@ -108,6 +125,25 @@ pub fn run_command(command: &str, args: &[&str], working_dir: Option<&Path>) ->
     Ok(())
 }
 
+// Starts a command in a process group of its own, so that it can be stopped
+// along with everything it started while flatplay carries on.
+pub fn spawn_command(command: &str, args: &[&str], working_dir: Option<&Path>) -> Result<Child> {
+    let (program, final_args) = host_command(command, args);
+
+    command_header(program, &final_args);
+    let mut cmd = Command::new(program);
+    cmd.args(&final_args)
+        .stdin(Stdio::null())
+        .stdout(Stdio::inherit())
+        .stderr(Stdio::inherit())
+        .process_group(0);
+    if let Some(dir) = working_dir {
+        cmd.current_dir(dir);
+    }
+    cmd.spawn()
+        .with_context(|| format!("Failed to start {command}"))
+}
+
 // Runs flatpak-builder, preferring the native binary, then the Flatpak app.
 pub fn flatpak_builder(args: &[&str], working_dir: Option<&Path>) -> Result<()> {
     if command_succeeds("flatpak-builder", &["--version"]) {
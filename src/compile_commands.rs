@@ -0,0 +1,199 @@
+use std::fs;
+use std::os::unix::fs::symlink;
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+
+use crate::utils::verbose;
+
+/// A directory as seen inside the build sandbox, and where it is on the host.
+pub type Mount = (String, PathBuf);
+
+/// Merges the compile databases of one or more build trees into `dest`, with
+/// the sandbox mounts in their paths replaced by host paths.
+pub fn export(databases: &[PathBuf], mounts: &[Mount], dest: &Path) -> Result<()> {
+    let mut entries = Vec::new();
+    for database in databases {
+        let content = fs::read_to_string(database)
+            .with_context(|| format!("Failed to read {}", database.display()))?;
+        let serde_json::Value::Array(database_entries) = serde_json::from_str(&content)
+            .with_context(|| format!("Failed to parse {}", database.display()))?
+        else {
+            anyhow::bail!("{} is not a compile database", database.display());
+        };
+        entries.extend(database_entries);
+    }
+    let mut entries = serde_json::Value::Array(entries);
+    map_json_paths(&mut entries, mounts);
+
+    let temp_file = tempfile::NamedTempFile::new_in(dest.parent().unwrap_or(Path::new(".")))?;
+    serde_json::to_writer_pretty(&temp_file, &entries)?;
+    temp_file.persist(dest)?;
+    Ok(())
+}
+
+/// Points `link` at `target`, unless `link` is a file that is not a symlink,
+/// which is left alone. Returns whether the link is in place.
+pub fn link(link: &Path, target: &Path) -> Result<bool> {
+    let target = link
+        .parent()
+        .and_then(|link_dir| target.strip_prefix(link_dir).ok())
+        .unwrap_or(target);
+    match fs::symlink_metadata(link) {
+        Ok(metadata) if !metadata.file_type().is_symlink() => {
+            verbose(format!(
+                "Not replacing {}, it is not a symlink",
+                link.display()
+            ));
+            return Ok(false);
+        }
+        Ok(_) if fs::read_link(link)? == target => return Ok(true),
+        Ok(_) => fs::remove_file(link)?,
+        Err(_) => {}
+    }
+    symlink(target, link)?;
+    Ok(true)
+}
+
+fn map_json_paths(value: &mut serde_json::Value, mounts: &[Mount]) {
+    match value {
+        serde_json::Value::String(text) => *text = map_paths(text, mounts),
+        serde_json::Value::Array(values) => {
+            for value in values {
+                map_json_paths(value, mounts);
+            }
+        }
+        serde_json::Value::Object(object) => {
+            for value in object.values_mut() {
+                map_json_paths(value, mounts);
+            }
+        }
+        _ => {}
+    }
+}
+
+const fn is_path_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '/' | '.' | '_' | '-' | '+')
+}
+
+// Whether a path can start at `index`: at the start of a word, or right after
+// a flag such as `-I` or `-isystem`, but not inside another path.
+fn starts_path(text: &str, index: usize) -> bool {
+    let word_start = text[..index]
+        .rfind(|c: char| !is_path_char(c))
+        .map_or(0, |position| position + 1);
+    let word = &text[word_start..index];
+    word.is_empty() || (word.starts_with('-') && !word.contains('/'))
+}
+
+/// Replaces the mount points in `text` with their host paths. Mounts are tried
+/// in order, so more specific ones have to come first.
+pub fn map_paths(text: &str, mounts: &[Mount]) -> String {
+    let mut mapped = String::with_capacity(text.len());
+    let mut index = 0;
+    while index < text.len() {
+        let rest = &text[index..];
+        let mount = mounts.iter().find(|(sandbox_path, _)| {
+            rest.strip_prefix(sandbox_path.as_str())
+                .is_some_and(|after| after.starts_with('/') || !after.starts_with(is_path_char))
+                && starts_path(text, index)
+        });
+        if let Some((sandbox_path, host_path)) = mount {
+            mapped.push_str(&host_path.to_string_lossy());
+            index += sandbox_path.len();
+        } else {
+            let c = rest.chars().next().unwrap_or_default();
+            mapped.push(c);
+            index += c.len_utf8();
+        }
+    }
+    mapped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn mounts() -> Vec<Mount> {
+        vec![
+            (
+                "/usr/lib/sdk/llvm20".to_string(),
+                PathBuf::from("/var/lib/flatpak/runtime/llvm20/files"),
+            ),
+            ("/usr".to_string(), PathBuf::from("/sdk/files")),
+            (
+                "/app".to_string(),
+                PathBuf::from("/project/.flatplay/repo/files"),
+            ),
+        ]
+    }
+
+    #[test]
+    fn test_map_paths() {
+        let mounts = mounts();
+        assert_eq!(
+            map_paths(
+                "/usr/bin/cc -I/app/include -isystem/usr/lib/sdk/llvm20/include \
+                 --sysroot=/usr -I /usr/include/glib-2.0 -c ../src/main.c",
+                &mounts
+            ),
+            "/sdk/files/bin/cc -I/project/.flatplay/repo/files/include \
+             -isystem/var/lib/flatpak/runtime/llvm20/files/include --sysroot=/sdk/files \
+             -I /sdk/files/include/glib-2.0 -c ../src/main.c"
+        );
+        assert_eq!(
+            map_paths("-Wl,-rpath,/app/lib \"/usr/include\"", &mounts),
+            "-Wl,-rpath,/project/.flatplay/repo/files/lib \"/sdk/files/include\""
+        );
+        // Only whole paths starting with a mount point are mapped.
+        assert_eq!(
+            map_paths(
+                "/home/me/usr/include /usrlocal/x /application -DX=/app",
+                &mounts
+            ),
+            "/home/me/usr/include /usrlocal/x /application -DX=/project/.flatplay/repo/files"
+        );
+    }
+
+    #[test]
+    fn test_export_and_link() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path().join("_build");
+        fs::create_dir_all(&build_dir).unwrap();
+        let database = build_dir.join("compile_commands.json");
+        fs::write(
+            &database,
+            r#"[{"directory": "/project/_build", "file": "../src/main.c",
+                "arguments": ["/usr/bin/cc", "-I/app/include", "-c", "../src/main.c"]}]"#,
+        )
+        .unwrap();
+
+        let exported = build_dir.join("exported.json");
+        export(&[database.clone(), database], &mounts(), &exported).unwrap();
+        let entries: serde_json::Value =
+            serde_json::from_str(&fs::read_to_string(&exported).unwrap()).unwrap();
+        assert_eq!(entries.as_array().unwrap().len(), 2);
+        assert_eq!(
+            entries[0]["arguments"],
+            serde_json::json!([
+                "/sdk/files/bin/cc",
+                "-I/project/.flatplay/repo/files/include",
+                "-c",
+                "../src/main.c"
+            ])
+        );
+
+        let link_path = dir.path().join("compile_commands.json");
+        assert!(link(&link_path, &exported).unwrap());
+        assert_eq!(
+            fs::read_link(&link_path).unwrap(),
+            Path::new("_build/exported.json")
+        );
+        assert!(link(&link_path, &exported).unwrap());
+
+        fs::remove_file(&link_path).unwrap();
+        fs::write(&link_path, "[]").unwrap();
+        assert!(!link(&link_path, &exported).unwrap());
+        assert_eq!(fs::read_to_string(&link_path).unwrap(), "[]");
+    }
+}
@@ -0,0 +1,135 @@
+use std::fs;
+use std::path::{Path, PathBuf};
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+
+const STAMP_FILE_NAME: &str = ".flatplay-configure.json";
+
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+struct ConfigureInputs {
+    source_dir: PathBuf,
+    config_opts: Vec<String>,
+    env: Vec<String>,
+}
+
+/// How the inputs of a configure step changed since it last succeeded.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Changes {
+    None,
+    /// Options were added, and nothing else changed. Configuring again on
+    /// top of the existing configuration is enough.
+    AddedOptions,
+    /// Options were removed or changed, the environment or source directory
+    /// differ, or nothing was recorded. Any earlier configuration is stale.
+    Other,
+}
+
+/// Records what a build directory was configured with, so that it is
+/// configured again exactly when that changes.
+pub struct ConfigureStamp {
+    path: PathBuf,
+    inputs: ConfigureInputs,
+}
+
+impl ConfigureStamp {
+    pub fn new(build_dir: &Path, source_dir: &Path, config_opts: &[&str], env: &[&str]) -> Self {
+        let mut env: Vec<String> = env.iter().map(ToString::to_string).collect();
+        env.sort();
+        Self {
+            path: build_dir.join(STAMP_FILE_NAME),
+            inputs: ConfigureInputs {
+                source_dir: source_dir.to_path_buf(),
+                config_opts: config_opts.iter().map(ToString::to_string).collect(),
+                env,
+            },
+        }
+    }
+
+    pub fn changes(&self) -> Changes {
+        let recorded = fs::read_to_string(&self.path)
+            .ok()
+            .and_then(|content| serde_json::from_str::<ConfigureInputs>(&content).ok());
+        match recorded {
+            Some(recorded) if recorded == self.inputs => Changes::None,
+            // An option that was set before keeps its value when it is left
+            // out on reconfiguring, so only additions can be applied.
+            Some(recorded)
+                if recorded.source_dir == self.inputs.source_dir
+                    && recorded.env == self.inputs.env
+                    && recorded
+                        .config_opts
+                        .iter()
+                        .all(|opt| self.inputs.config_opts.contains(opt)) =>
+            {
+                Changes::AddedOptions
+            }
+            _ => Changes::Other,
+        }
+    }
+
+    /// Forgets the last configure, so that a failed one is retried.
+    pub fn clear(&self) -> Result<()> {
+        if self.path.exists() {
+            fs::remove_file(&self.path)?;
+        }
+        Ok(())
+    }
+
+    pub fn record(&self) -> Result<()> {
+        if let Some(parent) = self.path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&self.path, serde_json::to_string_pretty(&self.inputs)?)?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_configure_stamp_changes() {
+        let dir = tempfile::tempdir().unwrap();
+        let build_dir = dir.path().join("_build");
+        let source_dir = dir.path().join("src");
+        let stamp =
+            |opts: &[&str], env: &[&str]| ConfigureStamp::new(&build_dir, &source_dir, opts, env);
+
+        let configured = stamp(&["-Dtests=true"], &["--env=A=1", "--env=B=2"]);
+        assert_eq!(configured.changes(), Changes::Other);
+        configured.record().unwrap();
+        assert_eq!(configured.changes(), Changes::None);
+
+        // The order of the environment does not matter.
+        assert_eq!(
+            stamp(&["-Dtests=true"], &["--env=B=2", "--env=A=1"]).changes(),
+            Changes::None
+        );
+        assert_eq!(
+            stamp(
+                &["-Dtests=true", "-Ddocs=true"],
+                &["--env=A=1", "--env=B=2"]
+            )
+            .changes(),
+            Changes::AddedOptions
+        );
+        // Removed or changed options would stick around in the configuration.
+        assert_eq!(
+            stamp(&[], &["--env=A=1", "--env=B=2"]).changes(),
+            Changes::Other
+        );
+        assert_eq!(
+            stamp(&["-Dtests=false"], &["--env=A=1", "--env=B=2"]).changes(),
+            Changes::Other
+        );
+        assert_eq!(
+            stamp(&["-Dtests=true"], &["--env=A=1"]).changes(),
+            Changes::Other
+        );
+
+        configured.clear().unwrap();
+        assert_eq!(configured.changes(), Changes::Other);
+    }
+}
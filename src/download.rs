@@ -0,0 +1,495 @@
+use std::fs;
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::{Duration, Instant};
+
+use anyhow::Result;
+
+use crate::checksum::{Checksum, Hasher};
+use crate::utils::{status_warn, verbose};
+
+// Progress is only worth showing for downloads that take a while.
+const PROGRESS_MIN_SIZE: u64 = 1024 * 1024;
+const PROGRESS_INTERVAL: Duration = Duration::from_millis(200);
+
+// Sources are fetched in parallel, and progress lines of concurrent downloads
+// would overwrite each other, so progress is only drawn while one is running.
+static ACTIVE_DOWNLOADS: AtomicUsize = AtomicUsize::new(0);
+
+/// How many times each URL is tried, and how long to wait before the first
+/// retry. The wait doubles after every failed attempt. Receiving the body of
+/// one attempt is limited to `body_timeout`, so that a stalled transfer is
+/// retried and resumed rather than waited on forever.
+struct RetryPolicy {
+    attempts: u32,
+    initial_delay: Duration,
+    body_timeout: Duration,
+}
+
+impl Default for RetryPolicy {
+    fn default() -> Self {
+        Self {
+            attempts: 4,
+            initial_delay: Duration::from_secs(1),
+            body_timeout: Duration::from_secs(5 * 60),
+        }
+    }
+}
+
+enum AttemptError {
+    // Worth trying the same URL again, keeping what was downloaded so far.
+    Retry(anyhow::Error),
+    // The URL will not work, move on to the next mirror.
+    Fatal(anyhow::Error),
+}
+
+/// Downloads the first of `urls` that works into `dest`, retrying flaky ones
+/// with exponential backoff. The file is written to `<dest>.part` while it is
+/// downloaded and hashed, and only renamed to `dest` once it is complete and
+/// matches `checksum`. An existing `.part` file is resumed where possible.
+pub fn download_file(urls: &[&str], dest: &Path, checksum: Option<&Checksum>) -> Result<()> {
+    download_with_policy(urls, dest, checksum, &RetryPolicy::default())
+}
+
+fn download_with_policy(
+    urls: &[&str],
+    dest: &Path,
+    checksum: Option<&Checksum>,
+    policy: &RetryPolicy,
+) -> Result<()> {
+    let agent = ureq::Agent::new_with_config(
+        ureq::Agent::config_builder()
+            .http_status_as_error(false)
+            .timeout_connect(Some(Duration::from_secs(30)))
+            .timeout_recv_response(Some(Duration::from_secs(60)))
+            .timeout_recv_body(Some(policy.body_timeout))
+            .build(),
+    );
+    if let Some(parent) = dest.parent() {
+        fs::create_dir_all(parent)?;
+    }
+    let mut partial = dest.as_os_str().to_owned();
+    partial.push(".part");
+    let partial = PathBuf::from(partial);
+
+    let mut errors = Vec::new();
+    let partial_len = || fs::metadata(&partial).map_or(0, |metadata| metadata.len());
+    for url in urls {
+        let mut delay = policy.initial_delay;
+        let mut attempt = 0;
+        while attempt < policy.attempts {
+            attempt += 1;
+            let before = partial_len();
+            match download_once(&agent, url, &partial, checksum) {
+                Ok(hash) => {
+                    if let (Some(checksum), Some(hash)) = (checksum, hash)
+                        && let Err(error) = checksum.check(&hash, url)
+                    {
+                        // A corrupt file cannot be resumed into a good one.
+                        fs::remove_file(&partial)?;
+                        errors.push(error.to_string());
+                        break;
+                    }
+                    fs::rename(&partial, dest)?;
+                    return Ok(());
+                }
+                Err(AttemptError::Fatal(error)) => {
+                    errors.push(format!("{url}: {error}"));
+                    break;
+                }
+                Err(AttemptError::Retry(error)) if attempt == policy.attempts => {
+                    errors.push(format!("{url}: {error}"));
+                }
+                Err(AttemptError::Retry(error)) => {
+                    // Large downloads can take longer than one attempt is
+                    // given, attempts that got further do not count.
+                    if partial_len() > before {
+                        attempt = 0;
+                        delay = policy.initial_delay;
+                    }
+                    status_warn(format!(
+                        "Download of {url} failed ({error}), retrying in {}s",
+                        delay.as_secs_f32()
+                    ));
+                    std::thread::sleep(delay);
+                    delay *= 2;
+                }
+            }
+        }
+    }
+
+    let first = urls.first().copied().unwrap_or_default();
+    Err(anyhow::anyhow!(
+        "Failed to download {first}:\n  {}",
+        errors.join("\n  ")
+    ))
+}
+
+// Downloads `url` into `partial`, returning the hash of the whole file when a
+// checksum is expected.
+fn download_once(
+    agent: &ureq::Agent,
+    url: &str,
+    partial: &Path,
+    checksum: Option<&Checksum>,
+) -> Result<Option<String>, AttemptError> {
+    let existing = fs::metadata(partial).map_or(0, |metadata| metadata.len());
+    let mut request = agent.get(url);
+    if existing > 0 {
+        verbose(format!("Resuming download of {url} at byte {existing}"));
+        request = request.header("Range", &format!("bytes={existing}-"));
+    }
+    let response = request
+        .call()
+        .map_err(|error| AttemptError::Retry(error.into()))?;
+
+    let status = response.status().as_u16();
+    let header = |name: &str| {
+        response
+            .headers()
+            .get(name)
+            .and_then(|value| value.to_str().ok())
+            .map(ToString::to_string)
+    };
+    let resumed = status == 206
+        && existing > 0
+        && header("content-range")
+            .is_some_and(|range| range.starts_with(&format!("bytes {existing}-")));
+    match status {
+        206 if !resumed => {
+            fs::remove_file(partial).map_err(|error| AttemptError::Fatal(error.into()))?;
+            return Err(AttemptError::Retry(anyhow::anyhow!(
+                "server resumed at the wrong offset"
+            )));
+        }
+        200..=299 => {}
+        // The partial file does not match what the server has, start over.
+        416 => {
+            fs::remove_file(partial).map_err(|error| AttemptError::Fatal(error.into()))?;
+            return Err(AttemptError::Retry(anyhow::anyhow!("HTTP 416")));
+        }
+        408 | 429 | 500..=599 => {
+            return Err(AttemptError::Retry(anyhow::anyhow!("HTTP {status}")));
+        }
+        _ => return Err(AttemptError::Fatal(anyhow::anyhow!("HTTP {status}"))),
+    }
+
+    let offset = if resumed { existing } else { 0 };
+    let total = header("content-length")
+        .and_then(|length| length.parse::<u64>().ok())
+        .map(|length| length + offset);
+    let fatal = |error: io::Error| AttemptError::Fatal(error.into());
+    let mut file = fs::OpenOptions::new()
+        .create(true)
+        .read(true)
+        .write(true)
+        .truncate(!resumed)
+        .open(partial)
+        .map_err(fatal)?;
+
+    // A resumed download is hashed from the start, the new bytes follow.
+    let mut hasher = checksum.map(Checksum::hasher);
+    if let Some(hasher) = &mut hasher {
+        hasher.copy(&mut file, &mut io::sink()).map_err(fatal)?;
+    }
+    file.seek(SeekFrom::End(0)).map_err(fatal)?;
+
+    let mut progress = Progress::new(url, offset, total);
+    let mut reader = response.into_body().into_reader();
+    let mut buffer = vec![0; 64 * 1024];
+    loop {
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(read) => read,
+            Err(error) if error.kind() == io::ErrorKind::Interrupted => continue,
+            Err(error) => {
+                progress.finish();
+                return Err(AttemptError::Retry(error.into()));
+            }
+        };
+        file.write_all(&buffer[..read]).map_err(fatal)?;
+        if let Some(hasher) = &mut hasher {
+            hasher.update(&buffer[..read]);
+        }
+        progress.advance(read as u64);
+    }
+    progress.finish();
+
+    if let Some(total) = total
+        && progress.done < total
+    {
+        return Err(AttemptError::Retry(anyhow::anyhow!(
+            "connection closed after {} of {total} bytes",
+            progress.done
+        )));
+    }
+    file.sync_all().map_err(fatal)?;
+    Ok(hasher.map(Hasher::finalize_hex))
+}
+
+// A single progress line on stderr, redrawn in place.
+struct Progress {
+    label: String,
+    done: u64,
+    total: Option<u64>,
+    enabled: bool,
+    last_draw: Option<Instant>,
+}
+
+impl Progress {
+    fn new(url: &str, done: u64, total: Option<u64>) -> Self {
+        let label = url.rsplit('/').next().unwrap_or(url).to_string();
+        let enabled = console::Term::stderr().is_term()
+            && total.is_none_or(|total| total >= PROGRESS_MIN_SIZE);
+        ACTIVE_DOWNLOADS.fetch_add(1, Ordering::SeqCst);
+        Self {
+            label,
+            done,
+            total,
+            enabled,
+            last_draw: None,
+        }
+    }
+
+    fn advance(&mut self, bytes: u64) {
+        self.done += bytes;
+        if !self.enabled
+            || ACTIVE_DOWNLOADS.load(Ordering::SeqCst) > 1
+            || self
+                .last_draw
+                .is_some_and(|last| last.elapsed() < PROGRESS_INTERVAL)
+        {
+            return;
+        }
+        self.last_draw = Some(Instant::now());
+        #[allow(clippy::cast_precision_loss)]
+        let mib = |bytes: u64| bytes as f64 / (1024.0 * 1024.0);
+        let line = match self.total {
+            Some(total) if total > 0 => format!(
+                "│ {} {:.1}/{:.1} MiB ({}%)",
+                self.label,
+                mib(self.done),
+                mib(total),
+                self.done * 100 / total
+            ),
+            _ => format!("│ {} {:.1} MiB", self.label, mib(self.done)),
+        };
+        eprint!("\r\x1b[2K{line}");
+    }
+
+    fn finish(&mut self) {
+        if self.enabled && self.last_draw.is_some() {
+            eprint!("\r\x1b[2K");
+        }
+        self.last_draw = None;
+    }
+}
+
+impl Drop for Progress {
+    fn drop(&mut self) {
+        ACTIVE_DOWNLOADS.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use mockito::Server;
+
+    const FAST_RETRIES: RetryPolicy = RetryPolicy {
+        attempts: 3,
+        initial_delay: Duration::from_millis(1),
+        body_timeout: Duration::from_secs(5),
+    };
+
+    #[test]
+    fn test_download_file_http_error() {
+        let dir = tempfile::tempdir().unwrap();
+        let archive_path = dir.path().join("missing.zip");
+
+        let mut server = Server::new();
+        let mock = server.mock("GET", "/missing.zip").with_status(404).create();
+
+        let url = format!("{}/missing.zip", server.url());
+
+        assert!(download_file(&[&url], &archive_path, None).is_err());
+        mock.assert();
+    }
+
+    #[test]
+    fn test_download_falls_back_to_mirrors() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.txt");
+
+        let mut server = Server::new();
+        let primary = server
+            .mock("GET", "/primary/file.txt")
+            .with_status(404)
+            .expect(1)
+            .create();
+        let broken_mirror = server
+            .mock("GET", "/broken/file.txt")
+            .with_status(503)
+            .expect(3)
+            .create();
+        let mirror = server
+            .mock("GET", "/mirror/file.txt")
+            .with_body("hello world")
+            .create();
+
+        let urls =
+            ["primary", "broken", "mirror"].map(|dir| format!("{}/{dir}/file.txt", server.url()));
+        let urls: Vec<&str> = urls.iter().map(String::as_str).collect();
+        download_with_policy(&urls, &dest, None, &FAST_RETRIES).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello world");
+
+        primary.assert();
+        broken_mirror.assert();
+        mirror.assert();
+    }
+
+    #[test]
+    fn test_download_retries_server_errors() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.txt");
+
+        let mut server = Server::new();
+        let failing = server
+            .mock("GET", "/file.txt")
+            .with_status(500)
+            .expect(2)
+            .create();
+        let working = server
+            .mock("GET", "/file.txt")
+            .with_body("hello world")
+            .expect(1)
+            .create();
+
+        let url = format!("{}/file.txt", server.url());
+        download_with_policy(&[&url], &dest, None, &FAST_RETRIES).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello world");
+
+        failing.assert();
+        working.assert();
+    }
+
+    #[test]
+    fn test_download_resumes_stalled_transfer() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.txt");
+
+        let mut server = Server::new();
+        let stalled = server
+            .mock("GET", "/file.txt")
+            .match_header("range", mockito::Matcher::Missing)
+            .with_chunked_body(|writer| {
+                writer.write_all(b"hello ")?;
+                writer.flush()?;
+                std::thread::sleep(Duration::from_secs(2));
+                writer.write_all(b"world")
+            })
+            .expect(1)
+            .create();
+        let resumed = server
+            .mock("GET", "/file.txt")
+            .match_header("range", "bytes=6-")
+            .with_status(206)
+            .with_header("content-range", "bytes 6-10/11")
+            .with_body("world")
+            .expect(1)
+            .create();
+
+        let url = format!("{}/file.txt", server.url());
+        let policy = RetryPolicy {
+            body_timeout: Duration::from_millis(300),
+            ..FAST_RETRIES
+        };
+        download_with_policy(&[&url], &dest, None, &policy).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello world");
+
+        stalled.assert();
+        resumed.assert();
+    }
+
+    #[test]
+    fn test_download_resumes_partial_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.txt");
+        fs::write(dir.path().join("file.txt.part"), "hello ").unwrap();
+
+        let mut server = Server::new();
+        let mock = server
+            .mock("GET", "/file.txt")
+            .match_header("range", "bytes=6-")
+            .with_status(206)
+            .with_header("content-range", "bytes 6-10/11")
+            .with_body("world")
+            .create();
+
+        let url = format!("{}/file.txt", server.url());
+        download_with_policy(&[&url], &dest, None, &FAST_RETRIES).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello world");
+
+        mock.assert();
+    }
+
+    #[test]
+    fn test_download_verifies_checksum_before_rename() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.txt");
+        let partial = dir.path().join("file.txt.part");
+        let hello = Checksum::new(
+            crate::checksum::Algorithm::Sha256,
+            "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9",
+        )
+        .unwrap();
+
+        let mut server = Server::new();
+        let corrupt = server
+            .mock("GET", "/corrupt/file.txt")
+            .with_body("hello corrupted world")
+            .create();
+        let resumed = server
+            .mock("GET", "/good/file.txt")
+            .match_header("range", "bytes=6-")
+            .with_status(206)
+            .with_header("content-range", "bytes 6-10/11")
+            .with_body("world")
+            .create();
+
+        let corrupt_url = format!("{}/corrupt/file.txt", server.url());
+        assert!(download_with_policy(&[&corrupt_url], &dest, Some(&hello), &FAST_RETRIES).is_err());
+        assert!(!dest.exists());
+        assert!(!partial.exists());
+
+        // The bytes that were already there count towards the checksum.
+        fs::write(&partial, "hello ").unwrap();
+        let good_url = format!("{}/good/file.txt", server.url());
+        download_with_policy(&[&good_url], &dest, Some(&hello), &FAST_RETRIES).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello world");
+        assert!(!partial.exists());
+
+        corrupt.assert();
+        resumed.assert();
+    }
+
+    #[test]
+    fn test_download_restarts_when_range_is_ignored() {
+        let dir = tempfile::tempdir().unwrap();
+        let dest = dir.path().join("file.txt");
+        fs::write(dir.path().join("file.txt.part"), "stale").unwrap();
+
+        let mut server = Server::new();
+        let mock = server
+            .mock("GET", "/file.txt")
+            .with_body("hello world")
+            .create();
+
+        let url = format!("{}/file.txt", server.url());
+        download_with_policy(&[&url], &dest, None, &FAST_RETRIES).unwrap();
+        assert_eq!(fs::read_to_string(&dest).unwrap(), "hello world");
+
+        mock.assert();
+    }
+}
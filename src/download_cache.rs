@@ -0,0 +1,134 @@
+use std::path::PathBuf;
+
+use anyhow::{Context, Result};
+
+use crate::checksum::Checksum;
+use crate::download::download_file;
+use crate::utils::{status, user_cache_dir, verbose};
+
+/// Downloaded sources, shared between projects and stored by their checksum so
+/// that a URL is only fetched once. Entries only appear once their download
+/// is complete and verified, so a corrupt download is never reused.
+pub struct DownloadCache {
+    dir: PathBuf,
+}
+
+impl DownloadCache {
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The cache under `$XDG_CACHE_HOME/flatplay/downloads`.
+    pub fn user_default() -> Self {
+        Self::new(user_cache_dir().join("flatplay").join("downloads"))
+    }
+
+    fn entry_path(&self, checksum: &Checksum) -> PathBuf {
+        self.dir.join(checksum.algorithm.name()).join(&checksum.hex)
+    }
+
+    /// Returns the cached file with the given checksum, if there is one.
+    pub fn get(&self, checksum: &Checksum) -> Option<PathBuf> {
+        let path = self.entry_path(checksum);
+        path.is_file().then_some(path)
+    }
+
+    /// Returns the cached file with the given checksum, downloading it from
+    /// the first of `urls` that works if it is not cached yet.
+    pub fn fetch(&self, urls: &[&str], checksum: &Checksum) -> Result<PathBuf> {
+        let url = urls.first().context("No URL to download from")?;
+        if let Some(path) = self.get(checksum) {
+            verbose(format!("Using cached download of {url}"));
+            return Ok(path);
+        }
+
+        let entry = self.entry_path(checksum);
+        status(format!("Downloading {url}"));
+        download_file(urls, &entry, Some(checksum))?;
+        Ok(entry)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::checksum::Algorithm;
+    use mockito::Server;
+    use std::fs;
+
+    const HELLO_SHA256: &str = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
+
+    fn hello_checksum() -> Checksum {
+        Checksum::new(Algorithm::Sha256, HELLO_SHA256).unwrap()
+    }
+
+    #[test]
+    fn test_fetch_downloads_once() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DownloadCache::new(dir.path().to_path_buf());
+
+        let mut server = Server::new();
+        let mock = server
+            .mock("GET", "/hello.txt")
+            .with_status(200)
+            .with_body("hello world")
+            .expect(1)
+            .create();
+        let url = format!("{}/hello.txt", server.url());
+
+        assert!(cache.get(&hello_checksum()).is_none());
+        let first = cache.fetch(&[&url], &hello_checksum()).unwrap();
+        let second = cache.fetch(&[&url], &hello_checksum()).unwrap();
+        assert_eq!(first, second);
+        assert_eq!(first, dir.path().join("sha256").join(HELLO_SHA256));
+        assert_eq!(fs::read_to_string(&first).unwrap(), "hello world");
+
+        mock.assert();
+    }
+
+    #[test]
+    fn test_fetch_rejects_corrupt_download() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DownloadCache::new(dir.path().to_path_buf());
+
+        let mut server = Server::new();
+        let mock = server
+            .mock("GET", "/hello.txt")
+            .with_status(200)
+            .with_body("hello corrupted world")
+            .create();
+        let url = format!("{}/hello.txt", server.url());
+
+        assert!(cache.fetch(&[&url], &hello_checksum()).is_err());
+        assert!(cache.get(&hello_checksum()).is_none());
+        let leftovers = fs::read_dir(dir.path().join("sha256")).unwrap().count();
+        assert_eq!(leftovers, 0);
+
+        mock.assert();
+    }
+
+    #[test]
+    fn test_entries_per_algorithm() {
+        let dir = tempfile::tempdir().unwrap();
+        let cache = DownloadCache::new(dir.path().to_path_buf());
+
+        let mut server = Server::new();
+        let mock = server
+            .mock("GET", "/hello.txt")
+            .with_status(200)
+            .with_body("hello world")
+            .expect(2)
+            .create();
+        let url = format!("{}/hello.txt", server.url());
+
+        let md5 = Checksum::new(Algorithm::Md5, "5eb63bbbe01eeed093cb22bb8f5acdc3").unwrap();
+        assert_eq!(
+            cache.fetch(&[&url], &md5).unwrap(),
+            dir.path().join("md5").join(&md5.hex)
+        );
+        assert!(cache.get(&hello_checksum()).is_none());
+        cache.fetch(&[&url], &hello_checksum()).unwrap();
+
+        mock.assert();
+    }
+}
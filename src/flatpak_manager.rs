@@ -1,69 +1,219 @@
+use std::collections::HashMap;
 use std::env;
 use std::fmt::Write;
 use std::fs;
 use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::time::Duration;
 
 use anyhow::{Context, Result};
 use colored::Colorize;
-use dialoguer::{Select, theme::SimpleTheme};
+use dialoguer::{Confirm, Select, theme::SimpleTheme};
 use nix::unistd::geteuid;
 
+use crate::archive::extract_archive;
 use crate::build_dirs::BuildDirs;
-use crate::command::{flatpak_builder, run_command};
-use crate::manifest::{BuildOptions, Manifest, Module, find_manifests_in_path};
-use crate::state::State;
+use crate::checksum::{Algorithm, Checksum};
+use crate::command::{
+    InterruptedError, flatpak_builder, is_interrupted_error, run_command, spawn_command,
+};
+use crate::compile_commands;
+use crate::configure_stamp::{Changes, ConfigureStamp};
+use crate::download::download_file;
+use crate::download_cache::DownloadCache;
+use crate::git_cache::{self, FetchOptions, GitCache};
+use crate::instance_lock::InstanceLock;
+use crate::manifest::{BuildOptions, Manifest, Module, ResolvedModule, find_manifests_in_path};
+use crate::profile::{self, Profile};
+use crate::runtimes;
+use crate::state::{ModuleHash, State};
 use crate::utils::{
-    build_font_config, download_file, extract_archive, get_a11y_bus_args, get_fonts_args,
-    get_host_env, guess_archive_type, path_to_str, status, status_info, status_success,
-    status_warn, verbose, verify_sha256_hex, version_less_than,
+    build_font_config, copy_verified, get_a11y_bus_args, get_fonts_args, get_host_env,
+    guess_archive_type, path_to_str, safe_join, status, status_error, status_info, status_success,
+    status_warn, verbose, verify_checksum, version_less_than,
 };
+use crate::watch::{self, SourceFilter, SourceWatcher};
 
-use sha2::{Digest, Sha256};
+// How often watch mode checks for an interruption or the application exiting.
+const WATCH_POLL: Duration = Duration::from_millis(200);
 
 struct BuildSandbox {
     fs_ws: String,
     fs_repo: String,
+    // Local sources outside the workspace are shared with the sandbox too.
+    fs_source: Option<String>,
     env_args: Vec<String>,
     path_overrides: Vec<String>,
 }
 
+// A module that flatplay builds incrementally itself, rather than leaving it
+// to flatpak-builder, along with where its build trees live.
+struct FastPathModule {
+    resolved: ResolvedModule,
+    build_dir: PathBuf,
+    cargo_target_dir: PathBuf,
+}
+
+// A git source whose revision is in the git cache, ready to be checked out.
+struct GitFetch {
+    url: String,
+    commit: String,
+    options: FetchOptions,
+}
+
+/// Settings from the command line that tweak how builds are prepared.
+pub struct Options {
+    /// Install missing runtimes and SDK extensions without asking.
+    pub install_missing: bool,
+    /// Remote that missing runtimes are installed from.
+    pub remote: String,
+    /// Never touch the network: only use cached downloads and existing checkouts.
+    pub offline: bool,
+    /// How many sources are fetched at the same time.
+    pub download_jobs: usize,
+    /// Modules that are built like the application module, as if they had
+    /// `x-flatplay-devel` set.
+    pub devel_modules: Vec<String>,
+    /// The build profile, either built-in or from `x-flatplay-profiles`.
+    pub profile: Option<String>,
+}
+
+impl Default for Options {
+    fn default() -> Self {
+        Self {
+            install_missing: false,
+            remote: "flathub".to_string(),
+            offline: false,
+            download_jobs: 4,
+            devel_modules: Vec::new(),
+            profile: None,
+        }
+    }
+}
+
 pub struct FlatpakManager<'a> {
     state: &'a mut State,
     manifest: Option<Manifest>,
     build_dirs: BuildDirs,
+    download_cache: DownloadCache,
+    git_cache: GitCache,
+    options: Options,
 }
 
 impl<'a> FlatpakManager<'a> {
-    fn application_module(&self) -> Result<Module> {
+    fn modules(&self) -> Result<Vec<ResolvedModule>> {
         let manifest = self.manifest.as_ref().context("No manifest available")?;
         let manifest_path = self
             .state
             .active_manifest
             .as_ref()
             .context("No active manifest")?;
-        manifest.application_module(manifest_path)
+        manifest.resolve_modules(manifest_path)
     }
 
-    fn last_module_name(&self) -> Result<String> {
-        let manifest = self.manifest.as_ref().context("No manifest available")?;
-        let manifest_path = self
-            .state
-            .active_manifest
-            .as_ref()
-            .context("No active manifest")?;
-        manifest.last_module_name(manifest_path)
+    fn is_devel_module(&self, module: &ResolvedModule) -> bool {
+        module.module.is_devel()
+            || self
+                .options
+                .devel_modules
+                .iter()
+                .any(|name| name == module.name())
     }
-    fn compute_manifest_hash(path: &Path) -> Result<String> {
-        let content = fs::read(path)?;
-        let mut hasher = Sha256::new();
-        hasher.update(&content);
-        let result = hasher.finalize();
 
-        let mut hash = String::with_capacity(64);
-        for b in result {
-            write!(&mut hash, "{b:02x}")?;
+    // The application module and the modules marked for development. As
+    // flatpak-builder can only stop before a module, every module from the
+    // first marked one onwards is built by flatplay.
+    fn fast_path_modules(&self) -> Result<Vec<FastPathModule>> {
+        let modules = self.modules()?;
+        if modules.is_empty() {
+            return Err(anyhow::anyhow!("Manifest has no modules"));
         }
-        Ok(hash)
+        for name in &self.options.devel_modules {
+            if !modules.iter().any(|module| module.name() == name) {
+                return Err(anyhow::anyhow!("Module '{name}' is not in the manifest"));
+            }
+        }
+
+        let application_index = modules.len() - 1;
+        let first = modules
+            .iter()
+            .position(|module| self.is_devel_module(module))
+            .unwrap_or(application_index);
+        Ok(modules
+            .into_iter()
+            .enumerate()
+            .skip(first)
+            .map(|(index, resolved)| {
+                if index != application_index && !self.is_devel_module(&resolved) {
+                    verbose(format!(
+                        "Building {} with flatplay as it comes after a development module",
+                        resolved.name()
+                    ));
+                }
+                // The application keeps the build trees of single module builds.
+                let module_name = (index != application_index).then(|| resolved.name());
+                let build_dir = match (&self.options.profile, module_name) {
+                    (Some(profile), _) => self
+                        .build_dirs
+                        .profile_build_system_dir(profile, module_name),
+                    (None, Some(module_name)) => {
+                        self.build_dirs.module_build_system_dir(module_name)
+                    }
+                    (None, None) => self.build_dirs.build_system_dir(),
+                };
+                let cargo_target_dir = module_name.map_or_else(
+                    || self.build_dirs.cargo_target_dir(),
+                    |module_name| self.build_dirs.module_cargo_target_dir(module_name),
+                );
+                FastPathModule {
+                    resolved,
+                    build_dir,
+                    cargo_target_dir,
+                }
+            })
+            .collect())
+    }
+
+    fn profile(&self) -> Result<Option<Profile>> {
+        let Some(name) = &self.options.profile else {
+            return Ok(None);
+        };
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        if let Some(profile) = manifest.x_flatplay_profiles.get(name) {
+            return Ok(Some(profile.clone()));
+        }
+        Profile::builtin(name).map(Some).with_context(|| {
+            let mut available: Vec<&str> = profile::BUILTIN.to_vec();
+            available.extend(manifest.x_flatplay_profiles.keys().map(String::as_str));
+            format!(
+                "Unknown profile '{name}', available profiles: {}",
+                available.join(", ")
+            )
+        })
+    }
+
+    // flatpak-builder builds the dependencies up to the first fast path module.
+    fn stop_at_module_name(&self) -> Result<String> {
+        let modules = self.fast_path_modules()?;
+        Ok(modules[0].resolved.name().to_string())
+    }
+
+    // The manifest is hashed in parts, so that changing a module only
+    // invalidates the builds of that module and the ones after it.
+    fn manifest_hashes(&self) -> Result<(String, Vec<ModuleHash>)> {
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        let module_hashes = self
+            .modules()?
+            .iter()
+            .map(|module| {
+                Ok(ModuleHash {
+                    name: module.name().to_string(),
+                    hash: module.hash()?,
+                })
+            })
+            .collect::<Result<_>>()?;
+        Ok((manifest.settings_hash()?, module_hashes))
     }
 
     fn find_manifests(&self) -> Result<Vec<PathBuf>> {
@@ -134,9 +284,17 @@ impl<'a> FlatpakManager<'a> {
             state,
             manifest,
             build_dirs,
+            download_cache: DownloadCache::user_default(),
+            git_cache: GitCache::user_default(),
+            options: Options::default(),
         }
     }
 
+    pub fn with_options(mut self, options: Options) -> Self {
+        self.options = options;
+        self
+    }
+
     fn check_required_version(manifest: &Manifest) -> Result<()> {
         let required = manifest.finish_args.iter().find_map(|arg| {
             let (key, value) = arg.split_once('=')?;
@@ -224,6 +382,14 @@ impl<'a> FlatpakManager<'a> {
         if self.manifest.is_some() {
             self.print_manifest_info();
             self.check_manifest_changed()?;
+            self.check_stop_at_changed()?;
+            // Asking flatpak about every ref is slow, so this is only done
+            // when the runtimes may have changed or are about to be used to
+            // set up the build directory.
+            if !self.state.runtimes_checked || !self.is_build_initialized() {
+                self.ensure_runtimes_installed()?;
+                self.state.runtimes_checked = true;
+            }
         }
 
         self.init()?;
@@ -232,6 +398,44 @@ impl<'a> FlatpakManager<'a> {
         Ok(())
     }
 
+    fn ensure_runtimes_installed(&self) -> Result<()> {
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        runtimes::install_missing(manifest, &mut |refs| self.install_refs(refs))
+    }
+
+    fn install_refs(&self, refs: &[String]) -> Result<()> {
+        let remote = &self.options.remote;
+        if self.options.offline {
+            return Err(anyhow::anyhow!(
+                "Missing runtimes: {}. They cannot be installed while offline.",
+                refs.join(", ")
+            ));
+        }
+        status_warn(format!("Missing runtimes: {}", refs.join(", ")));
+        if !self.options.install_missing {
+            let confirmed = console::Term::stderr().is_term()
+                && Confirm::with_theme(&SimpleTheme)
+                    .with_prompt(format!(
+                        "{} {}",
+                        "│".blue(),
+                        format!("Install them from {remote}?").blue()
+                    ))
+                    .default(true)
+                    .interact()?;
+            if !confirmed {
+                return Err(anyhow::anyhow!(
+                    "Missing runtimes: {}. Run with `--install-missing` or install them with `flatpak install --user {remote} {}`.",
+                    refs.join(", "),
+                    refs.join(" ")
+                ));
+            }
+        }
+
+        let mut args = vec!["install", "--user", "--noninteractive", remote.as_str()];
+        args.extend(refs.iter().map(String::as_str));
+        run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
+    }
+
     fn is_build_initialized(&self) -> bool {
         let metadata_file = self.build_dirs.metadata_file();
         let files_dir = self.build_dirs.files_dir();
@@ -241,83 +445,139 @@ impl<'a> FlatpakManager<'a> {
         metadata_file.is_file() && files_dir.is_dir() && var_dir.is_dir()
     }
 
-    fn init_build(&self) -> Result<()> {
-        let manifest = self.manifest.as_ref().context("No manifest available")?;
-        let repo_dir = self.build_dirs.repo_dir();
+    // These only take effect when the build directory is set up.
+    fn build_init_args(manifest: &Manifest, repo_dir: &Path) -> Result<Vec<String>> {
+        let mut args = vec!["build-init".to_string()];
+        args.extend(
+            manifest
+                .sdk_extensions
+                .iter()
+                .map(|extension| format!("--sdk-extension={extension}")),
+        );
+        args.extend([
+            path_to_str(repo_dir)?.to_string(),
+            manifest.id.clone(),
+            manifest.sdk.clone(),
+            manifest.runtime.clone(),
+            manifest.runtime_version.clone(),
+        ]);
+        Ok(args)
+    }
 
+    fn init_build(&self, init_args: &[String]) -> Result<()> {
         status(format!("{}", "Initializing build environment...".bold()));
-        run_command(
-            "flatpak",
-            &[
-                "build-init",
-                path_to_str(&repo_dir)?,
-                &manifest.id,
-                &manifest.sdk,
-                &manifest.runtime,
-                &manifest.runtime_version,
-            ],
-            Some(self.state.base_dir.as_path()),
-        )
+        let args: Vec<&str> = init_args.iter().map(String::as_str).collect();
+        run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
     }
 
-    fn init(&self) -> Result<()> {
+    fn init(&mut self) -> Result<()> {
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        let init_args = Self::build_init_args(manifest, &self.build_dirs.repo_dir())?;
         if self.is_build_initialized() {
+            if self.state.build_init_args == init_args {
+                return Ok(());
+            }
+            status_warn("SDK, runtime or SDK extensions changed, initializing the build again...");
+            fs::remove_dir_all(self.build_dirs.repo_dir())?;
+            self.state.reset();
+        }
+
+        self.init_build(&init_args)?;
+        self.state.build_init_args = init_args;
+        Ok(())
+    }
+
+    fn build_application(&self) -> Result<()> {
+        let modules = self.fast_path_modules()?;
+        for module in &modules {
+            self.build_module(module)?;
+        }
+        if let Err(error) = self.export_compile_commands(&modules) {
+            status_warn(format!("Failed to export compile_commands.json: {error:#}"));
+        }
+        Ok(())
+    }
+
+    // Language servers look for compile_commands.json in the project root, and
+    // need the paths in it to point at the SDK and build on the host.
+    fn export_compile_commands(&self, modules: &[FastPathModule]) -> Result<()> {
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        let databases: Vec<PathBuf> = modules
+            .iter()
+            .map(|module| module.build_dir.join("compile_commands.json"))
+            .filter(|database| database.is_file())
+            .collect();
+        if databases.is_empty() {
             return Ok(());
         }
 
-        self.init_build()?;
+        let mut mounts = vec![("/app".to_string(), self.build_dirs.files_dir())];
+        mounts.extend(runtimes::sdk_mounts(manifest)?);
+        let exported = self.build_dirs.build_dir().join("compile_commands.json");
+        compile_commands::export(&databases, &mounts, &exported)?;
+        if compile_commands::link(
+            &self.state.base_dir.join("compile_commands.json"),
+            &exported,
+        )? {
+            verbose("Exported compile_commands.json to the project root");
+        }
         Ok(())
     }
 
-    fn build_application(&self, rebuild: bool) -> Result<()> {
+    fn build_module(&self, module: &FastPathModule) -> Result<()> {
         let manifest = self.manifest.as_ref().context("No manifest available")?;
         let repo_dir = self.build_dirs.repo_dir();
         let repo_dir_str = path_to_str(&repo_dir)?;
 
-        self.download_application_sources()?;
+        self.download_module_sources(&module.resolved)?;
 
-        let module = self.application_module()?;
+        let is_cargo = Self::uses_cargo_target_dir(&module.resolved.module, manifest);
         let Module::Object {
             name,
             buildsystem,
             config_opts,
             build_commands,
-            build_options: module_build_options,
             post_install,
             ..
-        } = module
+        } = &module.resolved.module
         else {
-            return Err(anyhow::anyhow!(
-                "Application module is not a defined module"
-            ));
+            return Err(anyhow::anyhow!("Module is not a defined module"));
         };
 
-        let merged_config = manifest.merged_config_opts(config_opts.as_deref());
-        let module_bo = module_build_options.as_ref();
+        let profile_opts = self
+            .profile()?
+            .map(|profile| profile.config_opts(buildsystem.as_deref()))
+            .unwrap_or_default();
+        let merged_config = profile::merge_config_opts(
+            &manifest.merged_config_opts(config_opts.as_deref()),
+            &profile_opts,
+        );
         let num_cpus = std::thread::available_parallelism().map_or(1, std::num::NonZero::get);
+        let sandbox = self.module_sandbox(module, manifest)?;
 
         match buildsystem.as_deref() {
-            Some("meson") => self.run_meson(repo_dir_str, rebuild, &merged_config, module_bo)?,
+            Some("meson") => {
+                self.run_meson(module, &sandbox, repo_dir_str, &merged_config)?;
+            }
             Some("cmake" | "cmake-ninja") => {
-                self.run_cmake(repo_dir_str, rebuild, &merged_config, module_bo)?;
+                self.run_cmake(module, &sandbox, repo_dir_str, &merged_config)?;
             }
             Some("simple") => self.run_simple(
-                manifest,
+                module,
+                &sandbox,
                 repo_dir_str,
                 build_commands.as_ref(),
-                module_bo,
-                &name,
                 num_cpus,
+                is_cargo,
             )?,
             Some("qmake") => {
-                return Err(anyhow::anyhow!("qmake build system is not supported"));
+                self.run_qmake(module, &sandbox, repo_dir_str, &merged_config, num_cpus)?;
             }
-            _ => self.run_autotools(repo_dir_str, rebuild, &merged_config, module_bo, num_cpus)?,
+            _ => self.run_autotools(module, &sandbox, repo_dir_str, &merged_config, num_cpus)?,
         }
         if let Some(post_install) = post_install {
-            let sandbox = self.build_sandbox(module_bo, manifest);
-            for command in &post_install {
-                let processed = Self::substitute_vars(command, &manifest.id, &name, num_cpus);
+            for command in post_install {
+                let processed = Self::substitute_vars(command, &manifest.id, name, num_cpus);
                 let args = Self::build_command(&sandbox, repo_dir_str, &processed, &[], &[]);
                 run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
             }
@@ -326,30 +586,82 @@ impl<'a> FlatpakManager<'a> {
         Ok(())
     }
 
-    fn download_application_sources(&self) -> Result<()> {
-        let module = self.application_module()?;
-        let Module::Object { name, sources, .. } = module else {
-            return Err(anyhow::anyhow!(
-                "Application module is not a defined module"
-            ));
+    fn download_module_sources(&self, resolved: &ResolvedModule) -> Result<()> {
+        let ResolvedModule { module, base_dir } = resolved;
+        let Module::Object {
+            name,
+            sources,
+            build_options: module_build_options,
+            ..
+        } = module
+        else {
+            return Err(anyhow::anyhow!("Module is not a defined module"));
         };
-        let source_dir = self.build_dirs.build_dir().join(&name);
+        let source_dir = self.build_dirs.build_dir().join(name);
+
+        if self.options.offline {
+            let missing = self.missing_offline_sources(sources, base_dir)?;
+            if !missing.is_empty() {
+                return Err(anyhow::anyhow!(
+                    "Offline build of module '{name}' is missing sources:\n  {}",
+                    missing.join("\n  ")
+                ));
+            }
+        }
+
+        let mut fetched_git = self.prefetch_sources(sources, name, base_dir)?;
 
         if source_dir.exists() {
             fs::remove_dir_all(&source_dir)?;
         }
 
-        for source in &sources {
+        // Local directory sources are built in place, so sources that modify
+        // the source tree cannot be applied to them.
+        let is_dir_module = sources
+            .first()
+            .and_then(|source| source.get("type"))
+            .and_then(|v| v.as_str())
+            == Some("dir");
+
+        // Patches apply on top of everything else, in the order they are listed.
+        let mut patches = Vec::new();
+        for (index, source) in sources.iter().enumerate() {
             let Some(source_type) = source.get("type").and_then(|v| v.as_str()) else {
                 return Err(anyhow::anyhow!(
                     "Source in module '{name}' is missing a type field"
                 ));
             };
+            let dest_dir = match source.get("dest").and_then(|v| v.as_str()) {
+                Some(dest) => safe_join(&source_dir, dest)
+                    .with_context(|| format!("Invalid dest for source in module '{name}'"))?,
+                None => source_dir.clone(),
+            };
             match source_type {
-                "git" => self.handle_git_source(source, &name, &source_dir)?,
+                "git" => {
+                    let fetched = match fetched_git.remove(&index) {
+                        Some(fetched) => fetched,
+                        None => self.fetch_git_source(source, name, base_dir)?,
+                    };
+                    self.handle_git_source(source, name, &dest_dir, fetched)?;
+                }
                 "dir" => verbose(format!("Using local directory source for {name}")),
-                "archive" => self.handle_archive_source(source, &name, &source_dir)?,
-                "file" => self.handle_file_source(source, &name, &source_dir)?,
+                "archive" => self.handle_archive_source(source, name, &dest_dir, base_dir)?,
+                "file" => self.handle_file_source(source, name, &dest_dir, base_dir)?,
+                "patch" => patches.push((source, dest_dir)),
+                "shell" if is_dir_module => status_warn(format!(
+                    "Not running shell source commands in the local directory source of {name}"
+                )),
+                "shell" => self.handle_shell_source(
+                    source,
+                    name,
+                    &dest_dir,
+                    module_build_options.as_ref(),
+                )?,
+                "script" | "inline" if is_dir_module => status_warn(format!(
+                    "Not writing {source_type} sources into the local directory source of {name}"
+                )),
+                "script" => Self::handle_script_source(source, name, &dest_dir)?,
+                "inline" => Self::handle_inline_source(source, name, &dest_dir)?,
                 other => {
                     return Err(anyhow::anyhow!(
                         "Source type '{other}' in module '{name}' is not yet supported"
@@ -357,83 +669,391 @@ impl<'a> FlatpakManager<'a> {
                 }
             }
         }
+
+        if patches.is_empty() {
+            return Ok(());
+        }
+        if is_dir_module {
+            status_warn(format!(
+                "Not applying patches to the local directory source of {name}"
+            ));
+            return Ok(());
+        }
+        for (source, dest_dir) in patches {
+            fs::create_dir_all(&dest_dir)?;
+            Self::handle_patch_source(source, name, &dest_dir, base_dir)?;
+        }
         Ok(())
     }
 
-    fn handle_git_source(
+    // Lists the sources that an offline build cannot get from the download
+    // or git caches.
+    fn missing_offline_sources(
+        &self,
+        sources: &[serde_json::Value],
+        base_dir: &Path,
+    ) -> Result<Vec<String>> {
+        let mut missing = Vec::new();
+        for source in sources {
+            let source_type = source.get("type").and_then(|v| v.as_str());
+            let url = source.get("url").and_then(|v| v.as_str());
+            match (source_type, url) {
+                (Some("archive" | "file"), Some(url)) => {
+                    let cached = match Self::source_checksum(source)? {
+                        Some(checksum) => self.download_cache.get(&checksum).is_some(),
+                        None => false,
+                    };
+                    if !cached {
+                        missing.push(url.to_string());
+                    }
+                }
+                (Some("git"), _) => {
+                    let url = Self::git_url(source, "", base_dir)?;
+                    let revision = Self::git_revision(source);
+                    if !git_cache::is_local_url(&url)
+                        && self.git_cache.resolve(&url, revision)?.is_none()
+                    {
+                        missing.push(format!("{url} ({revision})"));
+                    }
+                }
+                _ => {}
+            }
+        }
+        Ok(missing)
+    }
+
+    // Like flatpak-builder, a git source without a revision follows the
+    // remote's default branch.
+    fn git_revision(source: &serde_json::Value) -> &str {
+        ["commit", "tag", "branch"]
+            .into_iter()
+            .find_map(|key| source.get(key).and_then(|v| v.as_str()))
+            .unwrap_or("HEAD")
+    }
+
+    // Local repositories given by `path` are fetched like any other remote.
+    fn git_url(source: &serde_json::Value, name: &str, base_dir: &Path) -> Result<String> {
+        if let Some(url) = source.get("url").and_then(|v| v.as_str()) {
+            return Ok(url.to_string());
+        }
+        let path = source.get("path").and_then(|v| v.as_str()).ok_or_else(|| {
+            anyhow::anyhow!("Git source in module '{name}' must specify url or path")
+        })?;
+        let repo = base_dir
+            .join(path)
+            .canonicalize()
+            .with_context(|| format!("Git repository not found: {path}"))?;
+        Ok(format!("file://{}", path_to_str(&repo)?))
+    }
+
+    // The URL of a source followed by its mirrors.
+    fn source_urls<'v>(source: &'v serde_json::Value, url: &'v str) -> Vec<&'v str> {
+        let mut urls = vec![url];
+        urls.extend(Self::string_list(source, "mirror-urls"));
+        urls
+    }
+
+    fn string_list<'v>(source: &'v serde_json::Value, key: &str) -> Vec<&'v str> {
+        source
+            .get(key)
+            .and_then(|v| v.as_array())
+            .into_iter()
+            .flatten()
+            .filter_map(|v| v.as_str())
+            .collect()
+    }
+
+    fn handle_shell_source(
         &self,
         source: &serde_json::Value,
         name: &str,
         source_dir: &Path,
+        module_build_options: Option<&BuildOptions>,
+    ) -> Result<()> {
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        let repo_dir = self.build_dirs.repo_dir();
+        let repo_dir_str = path_to_str(&repo_dir)?;
+        fs::create_dir_all(source_dir)?;
+        let source_dir_str = path_to_str(source_dir)?;
+        let sandbox = self.build_sandbox(module_build_options, manifest);
+        let fs_source = format!("--filesystem={source_dir_str}");
+        let cwd_source = format!("--build-dir={source_dir_str}");
+        let extra_fs = [fs_source.as_str(), cwd_source.as_str()];
+
+        for command in Self::string_list(source, "commands") {
+            status(format!("Running shell source command for {name}"));
+            let mut args = Self::sandbox_args(&sandbox, repo_dir_str, &extra_fs);
+            args.extend(["sh", "-c", command]);
+            run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+        }
+        Ok(())
+    }
+
+    fn handle_script_source(
+        source: &serde_json::Value,
+        name: &str,
+        source_dir: &Path,
     ) -> Result<()> {
-        let url = source
-            .get("url")
+        use std::os::unix::fs::PermissionsExt;
+
+        let filename = source
+            .get("dest-filename")
             .and_then(|v| v.as_str())
-            .ok_or_else(|| anyhow::anyhow!("Git source in module '{name}' must specify url"))?;
-        let tag = source.get("tag").and_then(|v| v.as_str());
-        let commit = source.get("commit").and_then(|v| v.as_str());
-        let branch = source.get("branch").and_then(|v| v.as_str());
-
-        match (commit, tag, branch) {
-            (Some(commit), _, _) => {
-                status(format!("Cloning {name} from {url} (commit {commit})"));
-                run_command(
-                    "git",
-                    &[
-                        "clone",
-                        "--recurse-submodules",
-                        url,
-                        path_to_str(source_dir)?,
-                    ],
-                    Some(self.state.base_dir.as_path()),
-                )?;
-                run_command(
-                    "git",
-                    &["-C", path_to_str(source_dir)?, "checkout", commit],
-                    Some(self.state.base_dir.as_path()),
-                )?;
-            }
-            (None, Some(tag), _) => {
-                status(format!("Cloning {name} from {url} (tag {tag})"));
-                run_command(
-                    "git",
-                    &[
-                        "clone",
-                        "--recurse-submodules",
-                        "--branch",
-                        tag,
-                        "--depth",
-                        "1",
-                        url,
-                        path_to_str(source_dir)?,
-                    ],
-                    Some(self.state.base_dir.as_path()),
-                )?;
+            .unwrap_or("autogen.sh");
+        let mut script = String::from("#!/bin/sh\n");
+        for command in Self::string_list(source, "commands") {
+            script.push_str(command);
+            script.push('\n');
+        }
+
+        let script_path = safe_join(source_dir, filename)
+            .with_context(|| format!("Invalid dest-filename for script in module '{name}'"))?;
+        if let Some(parent) = script_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(&script_path, script)?;
+        fs::set_permissions(&script_path, fs::Permissions::from_mode(0o755))?;
+        Ok(())
+    }
+
+    fn handle_inline_source(
+        source: &serde_json::Value,
+        name: &str,
+        source_dir: &Path,
+    ) -> Result<()> {
+        use base64::Engine;
+
+        let filename = source
+            .get("dest-filename")
+            .and_then(|v| v.as_str())
+            .ok_or_else(|| {
+                anyhow::anyhow!("Inline source in module '{name}' must specify dest-filename")
+            })?;
+        let contents = source
+            .get("contents")
+            .and_then(|v| v.as_str())
+            .unwrap_or_default();
+        let is_base64 = source
+            .get("base64")
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false);
+        let data = if is_base64 {
+            base64::engine::general_purpose::STANDARD
+                .decode(contents.trim())
+                .with_context(|| {
+                    format!("Inline source {filename} in module '{name}' is not valid base64")
+                })?
+        } else {
+            contents.as_bytes().to_vec()
+        };
+
+        let dest_path = safe_join(source_dir, filename).with_context(|| {
+            format!("Invalid dest-filename for inline source in module '{name}'")
+        })?;
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        fs::write(dest_path, data)?;
+        Ok(())
+    }
+
+    fn handle_patch_source(
+        source: &serde_json::Value,
+        name: &str,
+        source_dir: &Path,
+        base_dir: &Path,
+    ) -> Result<()> {
+        let paths: Vec<&str> = source
+            .get("path")
+            .and_then(|v| v.as_str())
+            .into_iter()
+            .chain(Self::string_list(source, "paths"))
+            .collect();
+        if paths.is_empty() {
+            return Err(anyhow::anyhow!(
+                "Patch source in module '{name}' must specify path or paths"
+            ));
+        }
+        let strip = source
+            .get("strip-components")
+            .and_then(serde_json::Value::as_u64)
+            .unwrap_or(1);
+        let use_git = source
+            .get("use-git")
+            .and_then(serde_json::Value::as_bool)
+            .unwrap_or(false);
+        let options = Self::string_list(source, "options");
+        let strip_flag = format!("-p{strip}");
+
+        for path in paths {
+            let patch_path = base_dir
+                .join(path)
+                .canonicalize()
+                .with_context(|| format!("Patch {path} for module '{name}' not found"))?;
+            let patch_path_str = path_to_str(&patch_path)?;
+            status(format!("Applying {path} to {name}"));
+            if use_git {
+                let mut args = vec!["apply", "-v", strip_flag.as_str()];
+                args.extend_from_slice(&options);
+                args.push(patch_path_str);
+                run_command("git", &args, Some(source_dir))?;
+            } else {
+                let mut args = vec![strip_flag.as_str(), "-i", patch_path_str];
+                args.extend_from_slice(&options);
+                run_command("patch", &args, Some(source_dir))?;
             }
-            (None, None, Some(branch)) => {
-                status(format!("Cloning {name} from {url} (branch {branch})"));
-                run_command(
-                    "git",
-                    &[
-                        "clone",
-                        "--recurse-submodules",
-                        "--branch",
-                        branch,
-                        "--depth",
-                        "1",
-                        url,
-                        path_to_str(source_dir)?,
-                    ],
-                    Some(self.state.base_dir.as_path()),
-                )?;
+        }
+        Ok(())
+    }
+
+    // Makes sure the git cache has the revision a source asks for.
+    fn fetch_git_source(
+        &self,
+        source: &serde_json::Value,
+        name: &str,
+        base_dir: &Path,
+    ) -> Result<GitFetch> {
+        let url = Self::git_url(source, name, base_dir)?;
+        let revision = Self::git_revision(source);
+        let commit = source.get("commit").and_then(|v| v.as_str());
+        let tag = source.get("tag").and_then(|v| v.as_str());
+        let is_branch = commit.is_none() && tag.is_none();
+        let flag = |key: &str| source.get(key).and_then(|v| v.as_bool()).unwrap_or(false);
+        let options = FetchOptions {
+            shallow: !flag("disable-shallow-clone"),
+            submodules: !flag("disable-submodules"),
+            fsck_objects: !flag("disable-fsckobjects"),
+            offline: self.options.offline,
+        };
+
+        let resolved = self.git_cache.update(&url, revision, is_branch, &options)?;
+        if commit.is_some()
+            && let Some(tag) = tag
+        {
+            self.git_cache.check_tag(&url, tag, &resolved, &options)?;
+        }
+        Ok(GitFetch {
+            url,
+            commit: resolved,
+            options,
+        })
+    }
+
+    fn handle_git_source(
+        &self,
+        source: &serde_json::Value,
+        name: &str,
+        source_dir: &Path,
+        fetched: GitFetch,
+    ) -> Result<()> {
+        let revision = Self::git_revision(source);
+        status(format!(
+            "Checking out {name} from {} ({revision})",
+            fetched.url
+        ));
+        self.git_cache
+            .checkout(&fetched.url, &fetched.commit, source_dir, &fetched.options)
+    }
+
+    // Fetches the sources that come from the network concurrently, so that
+    // staging them afterwards only has to copy from the caches. Sources that
+    // share a cache entry are fetched by the same worker, one after another.
+    fn prefetch_sources(
+        &self,
+        sources: &[serde_json::Value],
+        name: &str,
+        base_dir: &Path,
+    ) -> Result<HashMap<usize, GitFetch>> {
+        let mut groups: Vec<(String, Vec<usize>)> = Vec::new();
+        for (index, source) in sources.iter().enumerate() {
+            let key = match source.get("type").and_then(|v| v.as_str()) {
+                Some("git") => Self::git_url(source, name, base_dir)?,
+                Some("archive" | "file") if source.get("url").is_some() => {
+                    match Self::source_checksum(source)? {
+                        Some(checksum) => checksum.hex,
+                        None => continue,
+                    }
+                }
+                _ => continue,
+            };
+            match groups.iter_mut().find(|(group_key, _)| *group_key == key) {
+                Some((_, indices)) => indices.push(index),
+                None => groups.push((key, vec![index])),
             }
-            (None, None, None) => {
-                return Err(anyhow::anyhow!(
-                    "Git source in module '{name}' must specify one of: tag, commit, branch"
-                ));
+        }
+
+        let next_group = AtomicUsize::new(0);
+        let jobs = self.options.download_jobs.clamp(1, groups.len().max(1));
+        let mut results = std::thread::scope(|scope| {
+            let workers: Vec<_> = (0..jobs)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut results = Vec::new();
+                        while let Some((_, indices)) =
+                            groups.get(next_group.fetch_add(1, Ordering::SeqCst))
+                        {
+                            for &index in indices {
+                                let result = self.prefetch_source(&sources[index], name, base_dir);
+                                let failed = result.is_err();
+                                results.push((index, result));
+                                if failed {
+                                    break;
+                                }
+                            }
+                        }
+                        results
+                    })
+                })
+                .collect();
+            workers
+                .into_iter()
+                .flat_map(|worker| {
+                    worker
+                        .join()
+                        .unwrap_or_else(|panic| std::panic::resume_unwind(panic))
+                })
+                .collect::<Vec<_>>()
+        });
+        results.sort_by_key(|(index, _)| *index);
+        let mut fetched = HashMap::new();
+        for (index, result) in results {
+            if let Some(git) = result? {
+                fetched.insert(index, git);
             }
         }
-        Ok(())
+        Ok(fetched)
+    }
+
+    fn prefetch_source(
+        &self,
+        source: &serde_json::Value,
+        name: &str,
+        base_dir: &Path,
+    ) -> Result<Option<GitFetch>> {
+        if source.get("type").and_then(|v| v.as_str()) == Some("git") {
+            return self.fetch_git_source(source, name, base_dir).map(Some);
+        }
+        if let (Some(url), Some(checksum)) = (
+            source.get("url").and_then(|v| v.as_str()),
+            Self::source_checksum(source)?,
+        ) {
+            self.download_cache
+                .fetch(&Self::source_urls(source, url), &checksum)?;
+        }
+        Ok(None)
+    }
+
+    // The strongest of the checksums that a source specifies.
+    fn source_checksum(source: &serde_json::Value) -> Result<Option<Checksum>> {
+        Algorithm::PREFERENCE
+            .into_iter()
+            .find_map(|algorithm| {
+                source
+                    .get(algorithm.name())
+                    .and_then(|v| v.as_str())
+                    .map(|hex| Checksum::new(algorithm, hex))
+            })
+            .transpose()
     }
 
     fn handle_archive_source(
@@ -441,56 +1061,40 @@ impl<'a> FlatpakManager<'a> {
         source: &serde_json::Value,
         name: &str,
         source_dir: &Path,
+        base_dir: &Path,
     ) -> Result<()> {
-        let is_url = source.get("url").and_then(|v| v.as_str()).is_some();
-        let url = source
-            .get("url")
-            .and_then(|v| v.as_str())
+        let url = source.get("url").and_then(|v| v.as_str());
+        let location = url
             .or_else(|| source.get("path").and_then(|v| v.as_str()))
             .ok_or_else(|| {
                 anyhow::anyhow!("Archive source in module '{name}' must specify url or path")
             })?;
-        let sha256 = source
-            .get("sha256")
-            .and_then(|v| v.as_str())
-            .ok_or_else(|| {
-                anyhow::anyhow!("Archive source in module '{name}' must specify sha256")
-            })?;
+        let checksum = Self::source_checksum(source)?.ok_or_else(|| {
+            anyhow::anyhow!(
+                "Archive source in module '{name}' must specify one of: sha512, sha256, sha1, md5"
+            )
+        })?;
         let strip = source
             .get("strip-components")
             .and_then(serde_json::Value::as_u64)
             .and_then(|v| usize::try_from(v).ok())
             .unwrap_or(1);
-        let archive_type = source
-            .get("archive-type")
-            .and_then(|v| v.as_str())
-            .map_or_else(|| guess_archive_type(url), ToString::to_string);
-        let filename = source
-            .get("dest-filename")
-            .and_then(|v| v.as_str())
-            .map_or_else(
-                || {
-                    Path::new(url)
-                        .file_name()
-                        .and_then(|n| n.to_str())
-                        .unwrap_or("archive")
-                        .to_string()
-                },
-                ToString::to_string,
-            );
-        let archive_path = self.build_dirs.build_dir().join(&filename);
+        let archive_type = match source.get("archive-type").and_then(|v| v.as_str()) {
+            Some(archive_type) => archive_type,
+            None => guess_archive_type(location)
+                .with_context(|| format!("Invalid archive source in module '{name}'"))?,
+        };
 
-        if is_url {
-            status(format!("Downloading {name} from {url}"));
-            download_file(url, &archive_path)?;
+        let archive_path = if let Some(url) = url {
+            self.download_cache
+                .fetch(&Self::source_urls(source, url), &checksum)?
         } else {
-            status(format!("Copying {name} from {url}"));
-            fs::copy(url, &archive_path)?;
-        }
-        verify_sha256_hex(&archive_path, sha256)?;
-        extract_archive(&archive_path, &archive_type, source_dir, strip)?;
-        fs::remove_file(&archive_path).ok();
-        Ok(())
+            let path = base_dir.join(location);
+            verify_checksum(&path, &checksum)?;
+            path
+        };
+        status(format!("Extracting {name} from {location}"));
+        extract_archive(&archive_path, archive_type, source_dir, strip)
     }
 
     fn handle_file_source(
@@ -498,10 +1102,10 @@ impl<'a> FlatpakManager<'a> {
         source: &serde_json::Value,
         name: &str,
         source_dir: &Path,
+        base_dir: &Path,
     ) -> Result<()> {
-        let url = source
-            .get("url")
-            .and_then(|v| v.as_str())
+        let url = source.get("url").and_then(|v| v.as_str());
+        let location = url
             .or_else(|| source.get("path").and_then(|v| v.as_str()))
             .ok_or_else(|| {
                 anyhow::anyhow!("File source in module '{name}' must specify url or path")
@@ -511,7 +1115,7 @@ impl<'a> FlatpakManager<'a> {
             .and_then(|v| v.as_str())
             .map_or_else(
                 || {
-                    Path::new(url)
+                    Path::new(location)
                         .file_name()
                         .and_then(|n| n.to_str())
                         .unwrap_or("file")
@@ -520,25 +1124,31 @@ impl<'a> FlatpakManager<'a> {
                 ToString::to_string,
             );
 
-        if let Some(expected) = source.get("sha256").and_then(|v| v.as_str()) {
-            let is_url = source.get("url").and_then(|v| v.as_str()).is_some();
-            let temp_path = self.build_dirs.build_dir().join(&filename);
-            if is_url {
+        let dest_path = safe_join(source_dir, &filename)
+            .with_context(|| format!("Invalid dest-filename for file source in module '{name}'"))?;
+        if let Some(parent) = dest_path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+
+        match (url, Self::source_checksum(source)?) {
+            (Some(url), Some(checksum)) => {
+                let cached = self
+                    .download_cache
+                    .fetch(&Self::source_urls(source, url), &checksum)?;
+                fs::copy(cached, &dest_path)?;
+            }
+            (Some(url), None) => {
                 status(format!("Downloading {name} from {url}"));
-                download_file(url, &temp_path)?;
-            } else {
-                status(format!("Copying {name} from {url}"));
-                fs::copy(url, &temp_path)?;
+                download_file(&Self::source_urls(source, url), &dest_path, None)?;
             }
-            verify_sha256_hex(&temp_path, expected)?;
-            let dest_path = source_dir.join(&filename);
-            if let Some(parent) = dest_path.parent() {
-                fs::create_dir_all(parent)?;
+            (None, Some(checksum)) => {
+                status(format!("Copying {name} from {location}"));
+                copy_verified(&base_dir.join(location), &dest_path, &checksum)?;
+            }
+            (None, None) => {
+                status(format!("Copying {name} from {location}"));
+                fs::copy(base_dir.join(location), &dest_path)?;
             }
-            fs::rename(&temp_path, &dest_path)?;
-        } else {
-            status(format!("Copying {name} from {url}"));
-            fs::copy(url, source_dir.join(&filename))?;
         }
         Ok(())
     }
@@ -551,6 +1161,7 @@ impl<'a> FlatpakManager<'a> {
         BuildSandbox {
             fs_ws: format!("--filesystem={}", self.state.base_dir.display()),
             fs_repo: format!("--filesystem={}", self.build_dirs.repo_dir().display()),
+            fs_source: None,
             env_args: manifest
                 .merged_env(module_build_options)
                 .iter()
@@ -560,6 +1171,36 @@ impl<'a> FlatpakManager<'a> {
         }
     }
 
+    fn module_sandbox(&self, module: &FastPathModule, manifest: &Manifest) -> Result<BuildSandbox> {
+        let (module_build_options, buildsystem) = match &module.resolved.module {
+            Module::Object {
+                build_options,
+                buildsystem,
+                ..
+            } => (build_options.as_ref(), buildsystem.as_deref()),
+            Module::Reference(_) => (None, None),
+        };
+        let mut sandbox = self.build_sandbox(module_build_options, manifest);
+        if let Ok(source_dir) = self.module_source_dir(&module.resolved)
+            && !source_dir.starts_with(&self.state.base_dir)
+        {
+            sandbox.fs_source = Some(format!("--filesystem={}", source_dir.display()));
+        }
+
+        // Profile flags add to the manifest's, the last --env of a variable wins.
+        if let Some(profile) = self.profile()? {
+            let env = manifest.merged_env(module_build_options);
+            for (key, value) in profile.env(buildsystem) {
+                let value = match env.get(&key) {
+                    Some(flags) if key.ends_with("FLAGS") => format!("{flags} {value}"),
+                    _ => value,
+                };
+                sandbox.env_args.push(format!("--env={key}={value}"));
+            }
+        }
+        Ok(sandbox)
+    }
+
     fn sandbox_args<'s>(
         sandbox: &'s BuildSandbox,
         repo_dir_str: &'s str,
@@ -567,6 +1208,7 @@ impl<'a> FlatpakManager<'a> {
     ) -> Vec<&'s str> {
         let mut args: Vec<&str> =
             vec!["build", "--share=network", &sandbox.fs_ws, &sandbox.fs_repo];
+        args.extend(sandbox.fs_source.as_deref());
         args.extend_from_slice(extra_fs);
         args.extend(sandbox.env_args.iter().map(String::as_str));
         args.extend(sandbox.path_overrides.iter().map(String::as_str));
@@ -605,15 +1247,9 @@ impl<'a> FlatpakManager<'a> {
             )
     }
 
-    fn run_meson(
-        &self,
-        repo_dir_str: &str,
-        rebuild: bool,
-        config_opts: &[&str],
-        module_build_options: Option<&BuildOptions>,
-    ) -> Result<()> {
-        let manifest = self.manifest.as_ref().context("No manifest available")?;
-        let module = self.application_module()?;
+    // Local `dir` sources are built in place, everything else from the staged copy.
+    fn module_source_dir(&self, resolved: &ResolvedModule) -> Result<PathBuf> {
+        let ResolvedModule { module, base_dir } = resolved;
         let Module::Object {
             name,
             subdir,
@@ -621,126 +1257,129 @@ impl<'a> FlatpakManager<'a> {
             ..
         } = module
         else {
-            return Err(anyhow::anyhow!(
-                "Application module is not a defined module"
-            ));
+            return Err(anyhow::anyhow!("Module is not a defined module"));
         };
-        let source_dir = {
-            let base = if let Some(source) = sources.first() {
-                if let (Some("dir"), Some(path)) = (
-                    source.get("type").and_then(|v| v.as_str()),
-                    source.get("path").and_then(|v| v.as_str()),
-                ) {
-                    let manifest_path = self
-                        .state
-                        .active_manifest
-                        .as_ref()
-                        .context("No active manifest")?;
-                    let manifest_dir = manifest_path
-                        .parent()
-                        .context("Manifest path has no parent directory")?;
-                    manifest_dir.join(path)
-                } else {
-                    self.build_dirs.build_dir().join(&name)
-                }
-            } else {
-                self.build_dirs.build_dir().join(&name)
-            };
-            if let Some(subdir) = &subdir {
-                base.join(subdir)
-            } else {
-                base
+        let source_dir = if let Some(source) = sources.first()
+            && let (Some("dir"), Some(path)) = (
+                source.get("type").and_then(|v| v.as_str()),
+                source.get("path").and_then(|v| v.as_str()),
+            ) {
+            // The directory is used in place rather than staged at its `dest`,
+            // so a `subdir` below that `dest` is relative to the directory itself.
+            let dest = source.get("dest").and_then(|v| v.as_str()).unwrap_or("");
+            let subdir = subdir.as_deref().map(|subdir| {
+                Path::new(subdir)
+                    .strip_prefix(dest)
+                    .unwrap_or_else(|_| Path::new(subdir))
+            });
+            let dir = base_dir.join(path);
+            match subdir {
+                Some(subdir) => dir.join(subdir),
+                None => dir,
+            }
+        } else {
+            let staged = self.build_dirs.build_dir().join(name);
+            match &subdir {
+                Some(subdir) => safe_join(&staged, subdir)
+                    .with_context(|| format!("Invalid subdir for module '{name}'"))?,
+                None => staged,
             }
         };
-        let source_dir = source_dir
+        source_dir
             .canonicalize()
-            .context("Source directory not found")?;
+            .context("Source directory not found")
+    }
+
+    // The inputs of configuring a module, to tell when it has to be done again.
+    fn configure_stamp(
+        module: &FastPathModule,
+        sandbox: &BuildSandbox,
+        source_dir: &Path,
+        config_opts: &[&str],
+    ) -> ConfigureStamp {
+        let env: Vec<&str> = sandbox
+            .env_args
+            .iter()
+            .chain(&sandbox.path_overrides)
+            .map(String::as_str)
+            .collect();
+        ConfigureStamp::new(&module.build_dir, source_dir, config_opts, &env)
+    }
+
+    fn run_meson(
+        &self,
+        module: &FastPathModule,
+        sandbox: &BuildSandbox,
+        repo_dir_str: &str,
+        config_opts: &[&str],
+    ) -> Result<()> {
+        let source_dir = self.module_source_dir(&module.resolved)?;
         let source_dir_str = path_to_str(&source_dir)?;
-        let build_dir = self.build_dirs.build_system_dir();
-        let build_dir_str = path_to_str(&build_dir)?;
-        let sandbox = self.build_sandbox(module_build_options, manifest);
+        let build_dir_str = path_to_str(&module.build_dir)?;
         let fs_builddir = format!("--filesystem={build_dir_str}");
         let extra_fs = [fs_builddir.as_str()];
 
-        if !rebuild {
-            let mut args = Self::sandbox_args(&sandbox, repo_dir_str, &extra_fs);
+        // meson keeps the environment of the first setup, as well as options
+        // that are no longer given, so only added options can be applied to
+        // an existing build directory.
+        let stamp = Self::configure_stamp(module, sandbox, &source_dir, config_opts);
+        let configured = module
+            .build_dir
+            .join("meson-private/coredata.dat")
+            .is_file();
+        let setup_flag = match (configured, stamp.changes()) {
+            (true, Changes::None) => None,
+            (true, Changes::AddedOptions) => Some(Some("--reconfigure")),
+            (true, Changes::Other) => Some(Some("--wipe")),
+            (false, _) => Some(None),
+        };
+        if let Some(setup_flag) = setup_flag {
+            stamp.clear()?;
+            let mut args = Self::sandbox_args(sandbox, repo_dir_str, &extra_fs);
             args.extend(&["meson", "setup"]);
+            args.extend(setup_flag);
             args.extend_from_slice(config_opts);
             args.extend(&["--prefix=/app", source_dir_str, build_dir_str]);
             run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+            stamp.record()?;
         }
 
         {
             let ninja_cmd = format!("ninja -C {build_dir_str}");
-            let args = Self::build_command(&sandbox, repo_dir_str, &ninja_cmd, &extra_fs, &[]);
+            let args = Self::build_command(sandbox, repo_dir_str, &ninja_cmd, &extra_fs, &[]);
             run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
         }
         {
             let install_cmd = format!("meson install -C {build_dir_str}");
-            let args = Self::build_command(&sandbox, repo_dir_str, &install_cmd, &extra_fs, &[]);
+            let args = Self::build_command(sandbox, repo_dir_str, &install_cmd, &extra_fs, &[]);
             run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
         }
     }
 
     fn run_cmake(
         &self,
+        module: &FastPathModule,
+        sandbox: &BuildSandbox,
         repo_dir_str: &str,
-        rebuild: bool,
         config_opts: &[&str],
-        module_build_options: Option<&BuildOptions>,
     ) -> Result<()> {
-        let manifest = self.manifest.as_ref().context("No manifest available")?;
-        let module = self.application_module()?;
-        let Module::Object {
-            name,
-            subdir,
-            sources,
-            ..
-        } = module
-        else {
-            return Err(anyhow::anyhow!(
-                "Application module is not a defined module"
-            ));
-        };
-        let source_dir = {
-            let base = if let Some(source) = sources.first() {
-                if let (Some("dir"), Some(path)) = (
-                    source.get("type").and_then(|v| v.as_str()),
-                    source.get("path").and_then(|v| v.as_str()),
-                ) {
-                    let manifest_path = self
-                        .state
-                        .active_manifest
-                        .as_ref()
-                        .context("No active manifest")?;
-                    let manifest_dir = manifest_path
-                        .parent()
-                        .context("Manifest path has no parent directory")?;
-                    manifest_dir.join(path)
-                } else {
-                    self.build_dirs.build_dir().join(&name)
-                }
-            } else {
-                self.build_dirs.build_dir().join(&name)
-            };
-            if let Some(subdir) = &subdir {
-                base.join(subdir)
-            } else {
-                base
-            }
-        }
-        .canonicalize()
-        .context("Source directory not found")?;
+        let source_dir = self.module_source_dir(&module.resolved)?;
         let source_dir_str = path_to_str(&source_dir)?;
-        let build_dir = self.build_dirs.build_system_dir();
-        let build_dir_str = path_to_str(&build_dir)?;
-        let sandbox = self.build_sandbox(module_build_options, manifest);
+        let build_dir_str = path_to_str(&module.build_dir)?;
         let fs_builddir = format!("--filesystem={build_dir_str}");
         let extra_fs = [fs_builddir.as_str()];
 
-        if !rebuild {
+        // Starting from an empty cache drops options that were removed, and
+        // picks up a changed environment.
+        let stamp = Self::configure_stamp(module, sandbox, &source_dir, config_opts);
+        let cache = module.build_dir.join("CMakeCache.txt");
+        if !cache.is_file() || stamp.changes() != Changes::None {
+            stamp.clear()?;
+            if cache.is_file() {
+                fs::remove_file(&cache)?;
+            }
             let b_flag = format!("-B{build_dir_str}");
-            let mut args = Self::sandbox_args(&sandbox, repo_dir_str, &extra_fs);
+            let mut args = Self::sandbox_args(sandbox, repo_dir_str, &extra_fs);
             args.extend(&["cmake", "-G", "Ninja", &b_flag]);
             args.extend(&[
                 "-DCMAKE_EXPORT_COMPILE_COMMANDS=1",
@@ -750,122 +1389,208 @@ impl<'a> FlatpakManager<'a> {
             args.extend_from_slice(config_opts);
             args.push(source_dir_str);
             run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+            stamp.record()?;
         }
 
         {
             let ninja_cmd = format!("ninja -C {build_dir_str}");
-            let args = Self::build_command(&sandbox, repo_dir_str, &ninja_cmd, &extra_fs, &[]);
+            let args = Self::build_command(sandbox, repo_dir_str, &ninja_cmd, &extra_fs, &[]);
             run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
         }
         {
             let install_cmd = format!("ninja -C {build_dir_str} install");
-            let args = Self::build_command(&sandbox, repo_dir_str, &install_cmd, &extra_fs, &[]);
+            let args = Self::build_command(sandbox, repo_dir_str, &install_cmd, &extra_fs, &[]);
             run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
         }
     }
 
-    fn run_simple(
+    fn run_qmake(
         &self,
-        manifest: &Manifest,
+        module: &FastPathModule,
+        sandbox: &BuildSandbox,
         repo_dir_str: &str,
-        build_commands: Option<&Vec<String>>,
-        module_build_options: Option<&BuildOptions>,
-        module_name: &str,
+        config_opts: &[&str],
         num_cpus: usize,
     ) -> Result<()> {
-        if let Some(commands) = build_commands {
-            let sandbox = self.build_sandbox(module_build_options, manifest);
-            for command in commands {
-                let processed = Self::substitute_vars(command, &manifest.id, module_name, num_cpus);
-                let args = Self::build_command(&sandbox, repo_dir_str, &processed, &[], &[]);
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
-            }
+        let Module::Object { builddir, .. } = &module.resolved.module else {
+            return Err(anyhow::anyhow!("Module is not a defined module"));
+        };
+        let source_dir = self.module_source_dir(&module.resolved)?;
+        let source_dir_str = path_to_str(&source_dir)?;
+        // qmake writes its Makefiles into the working directory, so run
+        // everything from the build directory (or in-tree without builddir).
+        let build_dir = if builddir.unwrap_or(false) {
+            fs::create_dir_all(&module.build_dir)?;
+            module.build_dir.clone()
+        } else {
+            source_dir.clone()
+        };
+        let build_dir_str = path_to_str(&build_dir)?;
+        let fs_builddir = format!("--filesystem={build_dir_str}");
+        let cwd_builddir = format!("--build-dir={build_dir_str}");
+        let extra_fs = [fs_builddir.as_str(), cwd_builddir.as_str()];
+
+        let stamp = Self::configure_stamp(module, sandbox, &source_dir, config_opts);
+        if !build_dir.join("Makefile").is_file() || stamp.changes() != Changes::None {
+            stamp.clear()?;
+            let args = Self::qmake_command(
+                sandbox,
+                repo_dir_str,
+                &extra_fs,
+                config_opts,
+                source_dir_str,
+            );
+            run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+            stamp.record()?;
+        }
+
+        let jobs_flag = format!("-j{num_cpus}");
+        {
+            let make_args = [jobs_flag.as_str()];
+            let args = Self::build_command(sandbox, repo_dir_str, "make", &extra_fs, &make_args);
+            run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+        }
+        {
+            let make_args = ["install"];
+            let args = Self::build_command(sandbox, repo_dir_str, "make", &extra_fs, &make_args);
+            run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
         }
-        Ok(())
     }
 
-    fn run_autotools(
+    fn qmake_command<'s>(
+        sandbox: &'s BuildSandbox,
+        repo_dir_str: &'s str,
+        extra_fs: &'s [&'s str],
+        config_opts: &[&'s str],
+        source_dir_str: &'s str,
+    ) -> Vec<&'s str> {
+        let mut args = Self::sandbox_args(sandbox, repo_dir_str, extra_fs);
+        args.extend(&["qmake", "PREFIX=/app"]);
+        args.extend_from_slice(config_opts);
+        args.push(source_dir_str);
+        args
+    }
+
+    fn run_simple(
         &self,
+        module: &FastPathModule,
+        sandbox: &BuildSandbox,
         repo_dir_str: &str,
-        rebuild: bool,
-        config_opts: &[&str],
-        module_build_options: Option<&BuildOptions>,
+        build_commands: Option<&Vec<String>>,
         num_cpus: usize,
+        cargo: bool,
     ) -> Result<()> {
         let manifest = self.manifest.as_ref().context("No manifest available")?;
-        let module = self.application_module()?;
-        let Module::Object {
-            name,
-            builddir,
-            subdir,
-            ..
-        } = module
-        else {
-            return Err(anyhow::anyhow!(
-                "Application module is not a defined module"
-            ));
+        let Some(commands) = build_commands else {
+            return Ok(());
         };
-        let source_dir = {
-            let base = self.build_dirs.build_dir().join(&name);
-            if let Some(subdir) = &subdir {
-                base.join(subdir)
-            } else {
-                base
+
+        // Build commands run from the module's source directory, like flatpak-builder.
+        let cwd_arg = match self.module_source_dir(&module.resolved) {
+            Ok(source_dir) => Some(format!("--build-dir={}", path_to_str(&source_dir)?)),
+            Err(error) => {
+                verbose(format!(
+                    "Running build commands without a source dir: {error:#}"
+                ));
+                None
             }
         };
-        let use_builddir = builddir.unwrap_or(false);
-
-        let sandbox = self.build_sandbox(module_build_options, manifest);
-        let source_dir_str = path_to_str(&source_dir)?;
 
-        match (rebuild, use_builddir) {
-            (false, true) => {
-                let mut args = Self::sandbox_args(&sandbox, repo_dir_str, &[]);
-                let configure_path = format!("{source_dir_str}/configure");
-                args.extend(&[&configure_path, "--prefix=/app"]);
-                args.extend_from_slice(config_opts);
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+        // Keep cargo's target dir outside the (re-staged) sources so that
+        // `rebuild` only recompiles what changed.
+        let target_dir = &module.cargo_target_dir;
+        let target_dir_str = path_to_str(target_dir)?;
+        let fs_target = format!("--filesystem={target_dir_str}");
+        let env_target = format!("--env=CARGO_TARGET_DIR={target_dir_str}");
+        let mut extra_fs: Vec<&str> = cwd_arg.iter().map(String::as_str).collect();
+        if cargo {
+            verbose(format!(
+                "Using persistent cargo target dir {target_dir_str}"
+            ));
+            fs::create_dir_all(target_dir)?;
+            extra_fs.extend([fs_target.as_str(), env_target.as_str()]);
+        }
 
-                let build_dir = self.build_dirs.build_system_dir();
-                let build_dir_str = path_to_str(&build_dir)?;
-                let fs_builddir = format!("--filesystem={build_dir_str}");
-                let extra_fs = [fs_builddir.as_str()];
-                let jobs_flag = format!("-j{num_cpus}");
-                let make_args = ["V=0", jobs_flag.as_str(), "install"];
-                let args =
-                    Self::build_command(&sandbox, repo_dir_str, "make", &extra_fs, &make_args);
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
+        for command in commands {
+            let mut processed =
+                Self::substitute_vars(command, &manifest.id, module.resolved.name(), num_cpus);
+            if cargo {
+                processed = Self::map_cargo_target_paths(&processed, target_dir_str);
             }
-            (false, false) => {
-                let mut args = Self::sandbox_args(&sandbox, repo_dir_str, &[]);
-                let configure_path = format!("{source_dir_str}/configure");
-                args.extend(&[&configure_path, "--prefix=/app"]);
-                args.extend_from_slice(config_opts);
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+            let args = Self::build_command(sandbox, repo_dir_str, &processed, &extra_fs, &[]);
+            run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+        }
+        Ok(())
+    }
 
-                let jobs_flag = format!("-j{num_cpus}");
-                let make_args = ["V=0", jobs_flag.as_str(), "install"];
-                let args = Self::build_command(&sandbox, repo_dir_str, "make", &[], &make_args);
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
-            }
-            (true, true) => {
-                let build_dir = self.build_dirs.build_system_dir();
-                let build_dir_str = path_to_str(&build_dir)?;
-                let fs_builddir = format!("--filesystem={build_dir_str}");
-                let extra_fs = [fs_builddir.as_str()];
-                let jobs_flag = format!("-j{num_cpus}");
-                let make_args = ["V=0", jobs_flag.as_str(), "install"];
-                let args =
-                    Self::build_command(&sandbox, repo_dir_str, "make", &extra_fs, &make_args);
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
-            }
-            (true, false) => {
-                let jobs_flag = format!("-j{num_cpus}");
-                let make_args = ["V=0", jobs_flag.as_str(), "install"];
-                let args = Self::build_command(&sandbox, repo_dir_str, "make", &[], &make_args);
-                run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
-            }
+    // Whether a module's cargo builds go to the persistent cargo target dir.
+    // That is left alone when the manifest sets its own CARGO_TARGET_DIR, as
+    // its build commands then refer to that rather than to `target/`.
+    fn uses_cargo_target_dir(module: &Module, manifest: &Manifest) -> bool {
+        let build_options = match module {
+            Module::Object { build_options, .. } => build_options.as_ref(),
+            Module::Reference(_) => None,
+        };
+        module.is_cargo()
+            && !manifest
+                .merged_env(build_options)
+                .contains_key("CARGO_TARGET_DIR")
+    }
+
+    // Points `target/...` paths in build commands (e.g. `install target/release/app`)
+    // at the persistent cargo target dir.
+    fn map_cargo_target_paths(command: &str, target_dir: &str) -> String {
+        command
+            .split_whitespace()
+            .map(|word| {
+                word.strip_prefix("./target/")
+                    .or_else(|| word.strip_prefix("target/"))
+                    .map_or_else(|| word.to_string(), |rest| format!("{target_dir}/{rest}"))
+            })
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    fn run_autotools(
+        &self,
+        module: &FastPathModule,
+        sandbox: &BuildSandbox,
+        repo_dir_str: &str,
+        config_opts: &[&str],
+        num_cpus: usize,
+    ) -> Result<()> {
+        let Module::Object { builddir, .. } = &module.resolved.module else {
+            return Err(anyhow::anyhow!("Module is not a defined module"));
+        };
+        let source_dir = self.module_source_dir(&module.resolved)?;
+        let source_dir_str = path_to_str(&source_dir)?;
+        // configure writes its Makefiles into the working directory, like qmake.
+        let build_dir = if builddir.unwrap_or(false) {
+            fs::create_dir_all(&module.build_dir)?;
+            module.build_dir.clone()
+        } else {
+            source_dir.clone()
+        };
+        let build_dir_str = path_to_str(&build_dir)?;
+        let fs_builddir = format!("--filesystem={build_dir_str}");
+        let cwd_builddir = format!("--build-dir={build_dir_str}");
+        let extra_fs = [fs_builddir.as_str(), cwd_builddir.as_str()];
+
+        let stamp = Self::configure_stamp(module, sandbox, &source_dir, config_opts);
+        if !build_dir.join("Makefile").is_file() || stamp.changes() != Changes::None {
+            stamp.clear()?;
+            let mut args = Self::sandbox_args(sandbox, repo_dir_str, &extra_fs);
+            let configure_path = format!("{source_dir_str}/configure");
+            args.extend(&[&configure_path, "--prefix=/app"]);
+            args.extend_from_slice(config_opts);
+            run_command("flatpak", &args, Some(self.state.base_dir.as_path()))?;
+            stamp.record()?;
         }
+
+        let jobs_flag = format!("-j{num_cpus}");
+        let make_args = ["V=0", jobs_flag.as_str(), "install"];
+        let args = Self::build_command(sandbox, repo_dir_str, "make", &extra_fs, &make_args);
+        run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
     }
 
     fn build_dependencies(&mut self) -> Result<()> {
@@ -877,7 +1602,7 @@ impl<'a> FlatpakManager<'a> {
             .context("No active manifest")?;
         let repo_dir = self.build_dirs.repo_dir();
         let state_dir = self.build_dirs.flatpak_builder_dir();
-        let stop_at = self.last_module_name()?;
+        let stop_at = self.stop_at_module_name()?;
         flatpak_builder(
             &[
                 "--ccache",
@@ -894,10 +1619,17 @@ impl<'a> FlatpakManager<'a> {
             Some(self.state.base_dir.as_path()),
         )?;
         self.state.dependencies_built = true;
+        self.state.dependencies_stop_at = Some(stop_at);
         self.state.save()
     }
 
     pub fn update_dependencies(&mut self) -> Result<()> {
+        // flatpak-builder builds with --disable-download, so whatever it
+        // downloaded before is used as it is.
+        if self.options.offline {
+            status_warn("Offline, using previously downloaded dependency sources.");
+            return Ok(());
+        }
         status(format!("{}", "Updating dependencies...".bold()));
 
         let manifest_path = self
@@ -907,7 +1639,7 @@ impl<'a> FlatpakManager<'a> {
             .context("No active manifest")?;
         let repo_dir = self.build_dirs.repo_dir();
         let state_dir = self.build_dirs.flatpak_builder_dir();
-        let stop_at = self.last_module_name()?;
+        let stop_at = self.stop_at_module_name()?;
         flatpak_builder(
             &[
                 "--ccache",
@@ -926,26 +1658,126 @@ impl<'a> FlatpakManager<'a> {
     }
 
     fn check_manifest_changed(&mut self) -> Result<()> {
-        if let Some(manifest_path) = &self.state.active_manifest {
-            let hash = Self::compute_manifest_hash(manifest_path)?;
-
-            if let Some(stored_hash) = &self.state.manifest_hash {
-                if *stored_hash != hash {
-                    status_warn("Manifest changed, resetting build state...");
-                    self.state.reset();
-                    self.state.manifest_hash = Some(hash);
-                    self.state.save()?;
-                }
-            } else {
+        if self.state.active_manifest.is_none() {
+            return Ok(());
+        }
+        let (hash, module_hashes) = self.manifest_hashes()?;
+
+        match &self.state.manifest_hash {
+            None => {
                 status_warn("Manifest hash missing, resetting build state...");
                 self.state.reset();
-                self.state.manifest_hash = Some(hash);
-                self.state.save()?;
             }
+            Some(stored_hash) if *stored_hash != hash => {
+                status_warn("Manifest changed, resetting build state...");
+                self.state.reset();
+            }
+            Some(_) => {
+                let Some(index) = self.state.first_changed_module(&module_hashes) else {
+                    return Ok(());
+                };
+                // flatpak-builder reuses its cached builds of the modules before
+                // the changed one, fast path modules are rebuilt every time anyway.
+                let dependencies = module_hashes.len() - self.fast_path_modules()?.len();
+                if index < dependencies {
+                    status_warn(format!(
+                        "Module '{}' changed, rebuilding the dependencies from there...",
+                        module_hashes[index].name
+                    ));
+                    self.state.dependencies_updated = false;
+                    self.state.dependencies_built = false;
+                }
+                self.state.application_built = false;
+            }
+        }
+        self.state.manifest_hash = Some(hash);
+        self.state.module_hashes = module_hashes;
+        self.state.save()
+    }
+
+    // Marking a module for development or no longer doing so moves where
+    // flatpak-builder stops, so the dependencies it built before are either
+    // missing modules or have fast path builds in them.
+    fn check_stop_at_changed(&mut self) -> Result<()> {
+        if !self.state.dependencies_built {
+            return Ok(());
+        }
+        let stop_at = self.stop_at_module_name()?;
+        if self.state.dependencies_stop_at.as_deref() != Some(stop_at.as_str()) {
+            status_warn(format!(
+                "Dependencies are now built up to '{stop_at}', rebuilding them..."
+            ));
+            self.state.dependencies_updated = false;
+            self.state.dependencies_built = false;
+            self.state.application_built = false;
+            self.state.save()?;
         }
         Ok(())
     }
 
+    // flatpak-builder caches the build of each module in an ostree repo in its
+    // state dir, under a ref named after the manifest file and the module.
+    // Without that ref, the module and every module after it miss the cache.
+    fn builder_cache_ref(manifest_file: &str, module: &str) -> String {
+        let mut cache_ref = format!("{manifest_file}/");
+        for byte in module.bytes() {
+            if byte.is_ascii_alphanumeric() || matches!(byte, b'-' | b'_' | b'.') {
+                cache_ref.push(char::from(byte));
+            } else {
+                // Escaped like flatpak-builder does, with a signed char.
+                let _ = write!(cache_ref, "{:x}", i32::from(byte.cast_signed()));
+            }
+        }
+        cache_ref
+    }
+
+    /// Rebuilds a single module. Dependencies are rebuilt by flatpak-builder,
+    /// which takes the modules before it from its cache.
+    pub fn rebuild_module(&mut self, name: &str) -> Result<()> {
+        if let Some(module) = self
+            .fast_path_modules()?
+            .iter()
+            .find(|module| module.resolved.name() == name)
+        {
+            status(format!("{}", format!("Rebuilding {name}...").bold()));
+            return self.build_module(module);
+        }
+        if !self.modules()?.iter().any(|module| module.name() == name) {
+            return Err(anyhow::anyhow!("Module '{name}' is not in the manifest"));
+        }
+
+        status(format!("{}", format!("Rebuilding {name}...").bold()));
+        let manifest_path = self
+            .state
+            .active_manifest
+            .as_ref()
+            .context("No active manifest")?;
+        let manifest_file = manifest_path
+            .file_name()
+            .and_then(|file_name| file_name.to_str())
+            .context("Invalid manifest path")?;
+        let cache_ref = self
+            .build_dirs
+            .flatpak_builder_dir()
+            .join("cache/refs/heads")
+            .join(Self::builder_cache_ref(manifest_file, name));
+        if cache_ref.is_file() {
+            fs::remove_file(&cache_ref)?;
+        } else {
+            verbose(format!("No cached build of {name} to invalidate"));
+        }
+
+        if !self.state.dependencies_updated {
+            self.update_dependencies()?;
+        }
+        self.build_dependencies()?;
+        // flatpak-builder starts over from a clean repo, so the fast path
+        // modules have to go in again.
+        self.build_application()?;
+        self.state.application_built = true;
+        self.state.save()
+    }
+
     pub fn build(&mut self) -> Result<()> {
         if !self.state.dependencies_updated {
             self.update_dependencies()?;
@@ -953,18 +1785,164 @@ impl<'a> FlatpakManager<'a> {
         if !self.state.dependencies_built {
             self.build_dependencies()?;
         }
-        self.build_application(false)?;
+        self.build_application()?;
         self.state.application_built = true;
         self.state.save()
     }
 
     pub fn rebuild(&mut self) -> Result<()> {
         status(format!("{}", "Rebuilding application...".bold()));
-        self.build_application(true)?;
+        self.build_application()?;
         self.state.application_built = true;
         self.state.save()
     }
 
+    /// Builds the application incrementally, then runs its test suite in the
+    /// build sandbox. `display` gives the tests access to the display.
+    pub fn test(&mut self, display: bool) -> Result<()> {
+        if self.state.application_built {
+            self.rebuild()?;
+        } else {
+            self.build()?;
+        }
+
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        let repo_dir = self.build_dirs.repo_dir();
+        let repo_dir_str = path_to_str(&repo_dir)?;
+        let modules = self.fast_path_modules()?;
+        let module = modules.last().context("Manifest has no modules")?;
+        let num_cpus = std::thread::available_parallelism().map_or(1, std::num::NonZero::get);
+        let source_dir = self.module_source_dir(&module.resolved)?;
+        let build_dir_str = path_to_str(&module.build_dir)?;
+        let (commands, working_dir) = Self::test_commands(
+            &module.resolved.module,
+            &manifest.id,
+            source_dir,
+            &module.build_dir,
+            num_cpus,
+        )?;
+
+        let sandbox = self.module_sandbox(module, manifest)?;
+        let mut extra_args = vec![
+            format!("--filesystem={build_dir_str}"),
+            format!("--build-dir={}", path_to_str(&working_dir)?),
+        ];
+        let target_dir_str = path_to_str(&module.cargo_target_dir)?;
+        let is_cargo = Self::uses_cargo_target_dir(&module.resolved.module, manifest);
+        if is_cargo {
+            extra_args.push(format!("--filesystem={target_dir_str}"));
+            extra_args.push(format!("--env=CARGO_TARGET_DIR={target_dir_str}"));
+        }
+        if display {
+            extra_args.extend(Self::display_args());
+        }
+        let extra_args: Vec<&str> = extra_args.iter().map(String::as_str).collect();
+
+        status(format!("{}", "Running tests...".bold()));
+        for command in &commands {
+            let command = if is_cargo {
+                Self::map_cargo_target_paths(command, target_dir_str)
+            } else {
+                command.clone()
+            };
+            let args = Self::build_command(&sandbox, repo_dir_str, &command, &extra_args, &[]);
+            run_command("flatpak", &args, Some(self.state.base_dir.as_path()))
+                .context("Tests failed")?;
+        }
+        status_success("Tests passed");
+        Ok(())
+    }
+
+    // The commands that run the tests of a module, and the directory they run
+    // in. Without x-test-commands, the build system's own test runner is used.
+    fn test_commands(
+        module: &Module,
+        app_id: &str,
+        source_dir: PathBuf,
+        build_dir: &Path,
+        num_cpus: usize,
+    ) -> Result<(Vec<String>, PathBuf)> {
+        let Module::Object {
+            name,
+            buildsystem,
+            builddir,
+            x_test_commands,
+            ..
+        } = module
+        else {
+            return Err(anyhow::anyhow!("Module is not a defined module"));
+        };
+        let build_dir_str = path_to_str(build_dir)?;
+        Ok(match (x_test_commands, buildsystem.as_deref()) {
+            (Some(commands), _) => (
+                commands
+                    .iter()
+                    .map(|command| Self::substitute_vars(command, app_id, name, num_cpus))
+                    .collect(),
+                source_dir,
+            ),
+            (None, Some("meson")) => (
+                vec![format!("meson test -C {build_dir_str} --print-errorlogs")],
+                build_dir.to_path_buf(),
+            ),
+            (None, Some("cmake" | "cmake-ninja")) => (
+                vec![format!(
+                    "ctest --test-dir {build_dir_str} --output-on-failure"
+                )],
+                build_dir.to_path_buf(),
+            ),
+            (None, Some("simple")) => {
+                return Err(anyhow::anyhow!(
+                    "Module '{name}' has no x-test-commands to run its tests with"
+                ));
+            }
+            (None, _) if builddir.unwrap_or(false) => {
+                (vec!["make check".to_string()], build_dir.to_path_buf())
+            }
+            (None, _) => (vec!["make check".to_string()], source_dir),
+        })
+    }
+
+    // Lets UI tests open windows on the host's display.
+    fn display_args() -> Vec<String> {
+        let mut args: Vec<String> = [
+            "--socket=wayland",
+            "--socket=fallback-x11",
+            "--share=ipc",
+            "--device=dri",
+        ]
+        .into_iter()
+        .map(String::from)
+        .collect();
+        args.extend(Self::host_session_args());
+        args
+    }
+
+    // What the application gets from the host session: its environment, the
+    // accessibility bus and the fonts.
+    fn host_session_args() -> Vec<String> {
+        let host_env = get_host_env();
+        verbose(format!(
+            "Forwarding host env vars: {:?}",
+            host_env.keys().collect::<Vec<_>>()
+        ));
+        let mut args: Vec<String> = host_env
+            .into_iter()
+            .map(|(key, value)| format!("--env={key}={value}"))
+            .collect();
+
+        match get_a11y_bus_args() {
+            Ok(a11y_args) => args.extend(a11y_args),
+            Err(error) => verbose(format!("a11y bus not available: {error:#}")),
+        }
+
+        match build_font_config().and_then(|config_path| get_fonts_args(&config_path)) {
+            Ok(fonts_args) => args.extend(fonts_args),
+            Err(error) => verbose(format!("fonts not available: {error:#}")),
+        }
+        args
+    }
+
     pub fn build_and_run(&mut self) -> Result<()> {
         self.build()?;
         self.run()
@@ -993,39 +1971,21 @@ impl<'a> FlatpakManager<'a> {
             "--talk-name=org.a11y.Bus".to_string(),
         ];
 
-        let host_env = get_host_env();
-        verbose(format!(
-            "Forwarding host env vars: {:?}",
-            host_env.keys().collect::<Vec<_>>()
-        ));
-        args.extend(
-            host_env
-                .into_iter()
-                .map(|(key, value)| format!("--env={key}={value}")),
-        );
-
-        match get_a11y_bus_args() {
-            Ok(a11y_args) => args.extend(a11y_args),
-            Err(error) => verbose(format!("a11y bus not available: {error:#}")),
-        }
+        args.extend(Self::host_session_args());
 
         if with_dev_paths {
             args.push("--share=network".to_string());
             args.extend(sandbox.path_overrides.clone());
         }
 
-        match build_font_config().and_then(|config_path| get_fonts_args(&config_path)) {
-            Ok(fonts_args) => args.extend(fonts_args),
-            Err(error) => verbose(format!("fonts not available: {error:#}")),
-        }
-
         args.extend(manifest.finish_args_filtered());
         args.push(path_to_str(repo_dir)?.to_string());
 
         Ok(args)
     }
 
-    pub fn run(&self) -> Result<()> {
+    // The `flatpak` arguments that run the application from the build.
+    fn run_args(&self) -> Result<Vec<String>> {
         if !self.state.application_built {
             return Err(anyhow::anyhow!(
                 "Application not built. Please run `build` first."
@@ -1040,11 +2000,182 @@ impl<'a> FlatpakManager<'a> {
         if let Some(x_run_args) = &manifest.x_run_args {
             args.extend(x_run_args.clone());
         }
+        Ok(args)
+    }
 
+    pub fn run(&self) -> Result<()> {
+        let args = self.run_args()?;
         let args_str: Vec<&str> = args.iter().map(String::as_str).collect();
         run_command("flatpak", &args_str, Some(self.state.base_dir.as_path()))
     }
 
+    // The local directories that the fast path modules are built from.
+    fn watched_source_dirs(&self) -> Result<Vec<PathBuf>> {
+        let mut dirs = Vec::new();
+        for module in self.fast_path_modules()? {
+            let ResolvedModule { module, base_dir } = &module.resolved;
+            let Module::Object { sources, .. } = module else {
+                continue;
+            };
+            for source in sources {
+                if let (Some("dir"), Some(path)) = (
+                    source.get("type").and_then(|v| v.as_str()),
+                    source.get("path").and_then(|v| v.as_str()),
+                ) {
+                    let dir = base_dir
+                        .join(path)
+                        .canonicalize()
+                        .with_context(|| format!("Source directory {path} not found"))?;
+                    if !dirs
+                        .iter()
+                        .any(|watched: &PathBuf| dir.starts_with(watched))
+                    {
+                        dirs.retain(|watched: &PathBuf| !watched.starts_with(&dir));
+                        dirs.push(dir);
+                    }
+                }
+            }
+        }
+        if dirs.is_empty() {
+            return Err(anyhow::anyhow!(
+                "The application has no local `dir` sources to watch"
+            ));
+        }
+        Ok(dirs)
+    }
+
+    // Starts the application in its own process group, so that it can be
+    // restarted without stopping flatplay.
+    fn spawn_app(&self, instance_lock: &mut InstanceLock) -> Result<Child> {
+        let args = self.run_args()?;
+        let args_str: Vec<&str> = args.iter().map(String::as_str).collect();
+        let child = spawn_command("flatpak", &args_str, Some(self.state.base_dir.as_path()))?;
+        instance_lock.set_app_process_group(Some(child.id()))?;
+        Ok(child)
+    }
+
+    fn stop_app(app: Option<Child>, instance_lock: &mut InstanceLock) -> Result<()> {
+        if let Some(mut child) = app {
+            watch::stop_process_group(&mut child)?;
+            instance_lock.set_app_process_group(None)?;
+        }
+        Ok(())
+    }
+
+    /// Builds and runs the application, then rebuilds and restarts it whenever
+    /// its sources change, until interrupted.
+    // The active manifest and the module files it includes.
+    fn manifest_files(&self) -> Result<Vec<PathBuf>> {
+        let manifest = self.manifest.as_ref().context("No manifest available")?;
+        let manifest_path = self
+            .state
+            .active_manifest
+            .as_ref()
+            .context("No active manifest")?;
+        let mut files = vec![manifest_path.canonicalize()?];
+        files.extend(manifest.module_files(manifest_path)?);
+        Ok(files)
+    }
+
+    fn source_watcher(&self) -> Result<(SourceWatcher, Vec<PathBuf>)> {
+        let dirs = self.watched_source_dirs()?;
+        let manifest_files = self.manifest_files()?;
+        let base_dir = &self.state.base_dir;
+        let filter = SourceFilter::new(
+            base_dir,
+            &dirs,
+            vec![
+                self.build_dirs.build_dir(),
+                base_dir.join("compile_commands.json"),
+            ],
+        );
+        let watcher = SourceWatcher::new(&dirs, &manifest_files, filter)?;
+        for dir in &dirs {
+            status_info(format!("Watching {} for changes", dir.display()));
+        }
+        Ok((watcher, manifest_files))
+    }
+
+    // Rebuilds after changes. When the manifest or a module file changed, the
+    // manifest is loaded again first, and what is watched follows it.
+    fn rebuild_changed(
+        &mut self,
+        manifest_changed: &mut bool,
+        watcher: &mut SourceWatcher,
+        manifest_files: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        if *manifest_changed {
+            let manifest_path = self
+                .state
+                .active_manifest
+                .clone()
+                .context("No active manifest")?;
+            self.manifest = Some(Manifest::from_file(&manifest_path)?);
+            self.ensure_ready(false)?;
+            (*watcher, *manifest_files) = self.source_watcher()?;
+            *manifest_changed = false;
+        }
+        if self.state.application_built {
+            self.rebuild()
+        } else {
+            self.build()
+        }
+    }
+
+    pub fn watch(&mut self, instance_lock: &mut InstanceLock) -> Result<()> {
+        let base_dir = self.state.base_dir.clone();
+        // Watching starts before building, so that saves during a build count.
+        let (mut watcher, mut manifest_files) = self.source_watcher()?;
+        // Until the manifest is loaded again successfully.
+        let mut manifest_changed = false;
+
+        let mut result = self.build();
+        loop {
+            let mut app = match result {
+                Ok(()) => Some(self.spawn_app(instance_lock)?),
+                Err(error) if is_interrupted_error(&error) => return Err(error),
+                Err(error) => {
+                    status_error(format!("Error: {error}"));
+                    status_info("Waiting for changes to try again");
+                    None
+                }
+            };
+
+            let changed = loop {
+                if crate::is_interrupted() {
+                    Self::stop_app(app, instance_lock)?;
+                    return Err(InterruptedError.into());
+                }
+                if let Some(child) = &mut app
+                    && let Some(exit_status) = child.try_wait()?
+                {
+                    status_info(format!(
+                        "Application exited ({exit_status}), waiting for changes"
+                    ));
+                    instance_lock.set_app_process_group(None)?;
+                    app = None;
+                }
+                let changed = watcher.changes(WATCH_POLL)?;
+                if !changed.is_empty() {
+                    break changed;
+                }
+            };
+
+            Self::stop_app(app, instance_lock)?;
+            let changed_path = changed[0].strip_prefix(&base_dir).unwrap_or(&changed[0]);
+            status(format!(
+                "{}",
+                match changed.len() {
+                    1 => format!("{} changed", changed_path.display()),
+                    count => format!("{} and {} more changed", changed_path.display(), count - 1),
+                }
+                .bold()
+            ));
+            manifest_changed |= changed.iter().any(|path| manifest_files.contains(path));
+            result = self.rebuild_changed(&mut manifest_changed, &mut watcher, &mut manifest_files);
+        }
+    }
+
     pub fn export_bundle(&self) -> Result<()> {
         if !self.state.application_built {
             return Err(anyhow::anyhow!(
@@ -1219,10 +2350,6 @@ impl<'a> FlatpakManager<'a> {
             self.clean()?;
 
             self.state.active_manifest = Some(manifest_path.to_path_buf());
-
-            self.state.manifest_hash = Some(Self::compute_manifest_hash(manifest_path)?);
-
-            self.state.save()?;
         }
 
         self.manifest = if let Some(manifest) = manifest {
@@ -1231,6 +2358,13 @@ impl<'a> FlatpakManager<'a> {
             Some(Manifest::from_file(manifest_path)?)
         };
 
+        if should_clean {
+            let (hash, module_hashes) = self.manifest_hashes()?;
+            self.state.manifest_hash = Some(hash);
+            self.state.module_hashes = module_hashes;
+            self.state.save()?;
+        }
+
         Ok(())
     }
 
@@ -1251,3 +2385,303 @@ impl<'a> FlatpakManager<'a> {
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_handle_patch_source() {
+        let dir = tempfile::tempdir().unwrap();
+        let source_dir = dir.path().join("source");
+        fs::create_dir_all(&source_dir).unwrap();
+        fs::write(source_dir.join("hello.txt"), "hello\n").unwrap();
+        fs::write(
+            dir.path().join("fix.patch"),
+            "--- a/hello.txt\n+++ b/hello.txt\n@@ -1 +1 @@\n-hello\n+hello, world\n",
+        )
+        .unwrap();
+
+        let source = serde_json::json!({
+            "type": "patch",
+            "paths": ["fix.patch"],
+            "use-git": true
+        });
+        FlatpakManager::handle_patch_source(&source, "app", &source_dir, dir.path()).unwrap();
+        assert_eq!(
+            fs::read_to_string(source_dir.join("hello.txt")).unwrap(),
+            "hello, world\n"
+        );
+
+        let missing = serde_json::json!({ "type": "patch" });
+        assert!(
+            FlatpakManager::handle_patch_source(&missing, "app", &source_dir, dir.path()).is_err()
+        );
+    }
+
+    #[test]
+    fn test_handle_script_and_inline_sources() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = tempfile::tempdir().unwrap();
+        let source_dir = dir.path();
+
+        let script = serde_json::json!({
+            "type": "script",
+            "commands": ["echo one", "echo two"]
+        });
+        FlatpakManager::handle_script_source(&script, "app", source_dir).unwrap();
+        let script_path = source_dir.join("autogen.sh");
+        assert_eq!(
+            fs::read_to_string(&script_path).unwrap(),
+            "#!/bin/sh\necho one\necho two\n"
+        );
+        assert_eq!(
+            fs::metadata(&script_path).unwrap().permissions().mode() & 0o777,
+            0o755
+        );
+
+        let inline = serde_json::json!({
+            "type": "inline",
+            "contents": "[Desktop Entry]\n",
+            "dest-filename": "data/app.desktop"
+        });
+        FlatpakManager::handle_inline_source(&inline, "app", source_dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(source_dir.join("data/app.desktop")).unwrap(),
+            "[Desktop Entry]\n"
+        );
+
+        let encoded = serde_json::json!({
+            "type": "inline",
+            "contents": "aGVsbG8=",
+            "base64": true,
+            "dest-filename": "hello.txt"
+        });
+        FlatpakManager::handle_inline_source(&encoded, "app", source_dir).unwrap();
+        assert_eq!(
+            fs::read_to_string(source_dir.join("hello.txt")).unwrap(),
+            "hello"
+        );
+
+        let unnamed = serde_json::json!({ "type": "inline", "contents": "x" });
+        assert!(FlatpakManager::handle_inline_source(&unnamed, "app", source_dir).is_err());
+
+        let escaping = serde_json::json!({
+            "type": "inline",
+            "contents": "x",
+            "dest-filename": "../outside.txt"
+        });
+        assert!(FlatpakManager::handle_inline_source(&escaping, "app", source_dir).is_err());
+    }
+
+    #[test]
+    fn test_builder_cache_ref() {
+        assert_eq!(
+            FlatpakManager::builder_cache_ref("org.example.App.json", "lib_foo-1.2"),
+            "org.example.App.json/lib_foo-1.2"
+        );
+        assert_eq!(
+            FlatpakManager::builder_cache_ref("app.yml", "lib foo/é"),
+            "app.yml/lib20foo2fffffffc3ffffffa9"
+        );
+    }
+
+    #[test]
+    fn test_test_commands() {
+        let source_dir = PathBuf::from("/project");
+        let build_dir = Path::new("/project/.flatplay/_build");
+        let commands = |module: serde_json::Value| {
+            let module: Module = serde_json::from_value(module).unwrap();
+            FlatpakManager::test_commands(
+                &module,
+                "org.example.App",
+                source_dir.clone(),
+                build_dir,
+                4,
+            )
+        };
+
+        assert_eq!(
+            commands(serde_json::json!({
+                "name": "app",
+                "buildsystem": "meson",
+                "x-test-commands": ["make -j${FLATPAK_BUILDER_N_JOBS} test"]
+            }))
+            .unwrap(),
+            (vec!["make -j4 test".to_string()], source_dir.clone())
+        );
+        assert_eq!(
+            commands(serde_json::json!({ "name": "app", "buildsystem": "meson" })).unwrap(),
+            (
+                vec!["meson test -C /project/.flatplay/_build --print-errorlogs".to_string()],
+                build_dir.to_path_buf()
+            )
+        );
+        assert_eq!(
+            commands(serde_json::json!({ "name": "app", "buildsystem": "cmake-ninja" })).unwrap(),
+            (
+                vec!["ctest --test-dir /project/.flatplay/_build --output-on-failure".to_string()],
+                build_dir.to_path_buf()
+            )
+        );
+        assert!(commands(serde_json::json!({ "name": "app", "buildsystem": "simple" })).is_err());
+        assert_eq!(
+            commands(serde_json::json!({ "name": "app", "builddir": true })).unwrap(),
+            (vec!["make check".to_string()], build_dir.to_path_buf())
+        );
+        assert_eq!(
+            commands(serde_json::json!({ "name": "app" })).unwrap(),
+            (vec!["make check".to_string()], source_dir.clone())
+        );
+    }
+
+    #[test]
+    fn test_qmake_command() {
+        let sandbox = BuildSandbox {
+            fs_ws: "--filesystem=/project".to_string(),
+            fs_repo: "--filesystem=/project/.flatplay/repo".to_string(),
+            fs_source: None,
+            env_args: vec!["--env=CC=gcc".to_string()],
+            path_overrides: Vec::new(),
+        };
+        let extra_fs = [
+            "--filesystem=/project/.flatplay/_build/app",
+            "--build-dir=/project/.flatplay/_build/app",
+        ];
+        assert_eq!(
+            FlatpakManager::qmake_command(
+                &sandbox,
+                "/project/.flatplay/repo",
+                &extra_fs,
+                &["CONFIG+=debug", "QMAKE_CXXFLAGS+=-Wall"],
+                "/project",
+            ),
+            [
+                "build",
+                "--share=network",
+                "--filesystem=/project",
+                "--filesystem=/project/.flatplay/repo",
+                "--filesystem=/project/.flatplay/_build/app",
+                "--build-dir=/project/.flatplay/_build/app",
+                "--env=CC=gcc",
+                "/project/.flatplay/repo",
+                "qmake",
+                "PREFIX=/app",
+                "CONFIG+=debug",
+                "QMAKE_CXXFLAGS+=-Wall",
+                "/project"
+            ]
+        );
+    }
+
+    #[test]
+    fn test_build_init_args() {
+        let manifest = |extensions: serde_json::Value| -> Manifest {
+            serde_json::from_value(serde_json::json!({
+                "id": "org.example.App",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "47",
+                "sdk-extensions": extensions,
+                "command": "app",
+                "modules": []
+            }))
+            .unwrap()
+        };
+        let repo_dir = Path::new("/project/.flatplay/repo");
+        let without_extensions =
+            FlatpakManager::build_init_args(&manifest(serde_json::json!([])), repo_dir).unwrap();
+        assert_eq!(
+            without_extensions,
+            [
+                "build-init",
+                "/project/.flatplay/repo",
+                "org.example.App",
+                "org.gnome.Sdk",
+                "org.gnome.Platform",
+                "47"
+            ]
+        );
+        let with_extension = FlatpakManager::build_init_args(
+            &manifest(serde_json::json!([
+                "org.freedesktop.Sdk.Extension.rust-stable"
+            ])),
+            repo_dir,
+        )
+        .unwrap();
+        assert_ne!(with_extension, without_extensions);
+        assert_eq!(
+            with_extension[1],
+            "--sdk-extension=org.freedesktop.Sdk.Extension.rust-stable"
+        );
+    }
+
+    #[test]
+    fn test_uses_cargo_target_dir() {
+        let manifest = |env: serde_json::Value| -> Manifest {
+            serde_json::from_value(serde_json::json!({
+                "id": "org.example.App",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "47",
+                "command": "app",
+                "build-options": { "env": env },
+                "modules": []
+            }))
+            .unwrap()
+        };
+        let module = |value: serde_json::Value| serde_json::from_value::<Module>(value).unwrap();
+        let app = module(serde_json::json!({
+            "name": "app",
+            "buildsystem": "simple",
+            "build-commands": ["cargo build --release"]
+        }));
+
+        assert!(FlatpakManager::uses_cargo_target_dir(
+            &app,
+            &manifest(serde_json::json!({}))
+        ));
+        assert!(!FlatpakManager::uses_cargo_target_dir(
+            &app,
+            &manifest(serde_json::json!({ "CARGO_TARGET_DIR": "/run/build/app/out" }))
+        ));
+        assert!(!FlatpakManager::uses_cargo_target_dir(
+            &module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "simple",
+                "build-commands": ["cargo build --release"],
+                "build-options": { "env": { "CARGO_TARGET_DIR": "out" } }
+            })),
+            &manifest(serde_json::json!({}))
+        ));
+        assert!(!FlatpakManager::uses_cargo_target_dir(
+            &module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "simple",
+                "build-commands": ["make"]
+            })),
+            &manifest(serde_json::json!({}))
+        ));
+    }
+
+    #[test]
+    fn test_map_cargo_target_paths() {
+        let target = "/project/.flatplay/cargo-target";
+        assert_eq!(
+            FlatpakManager::map_cargo_target_paths(
+                "install -Dm755 ./target/release/app -t /app/bin/",
+                target
+            ),
+            "install -Dm755 /project/.flatplay/cargo-target/release/app -t /app/bin/"
+        );
+        assert_eq!(
+            FlatpakManager::map_cargo_target_paths("cp target/release/app /app/bin", target),
+            "cp /project/.flatplay/cargo-target/release/app /app/bin"
+        );
+        assert_eq!(
+            FlatpakManager::map_cargo_target_paths("cargo build --release", target),
+            "cargo build --release"
+        );
+    }
+}
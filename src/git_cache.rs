@@ -0,0 +1,659 @@
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+
+use anyhow::{Context, Result};
+use nix::fcntl::{Flock, FlockArg};
+use sha2::{Digest, Sha256};
+
+use crate::command::{command_output, is_interrupted_error, run_command};
+use crate::utils::{path_to_str, safe_join, status, user_cache_dir, verbose};
+
+/// How git sources are fetched, following the matching git source options of
+/// flatpak-builder.
+#[derive(Debug, Clone, Copy)]
+pub struct FetchOptions {
+    /// Fetch only the requested revision instead of the whole history.
+    pub shallow: bool,
+    /// Check out submodules as well.
+    pub submodules: bool,
+    /// Check the integrity of fetched objects.
+    pub fsck_objects: bool,
+    /// Never fetch, only use what is already mirrored.
+    pub offline: bool,
+}
+
+impl Default for FetchOptions {
+    fn default() -> Self {
+        Self {
+            shallow: true,
+            submodules: true,
+            fsck_objects: true,
+            offline: false,
+        }
+    }
+}
+
+/// Bare mirrors of git sources, one per URL, shared between projects. Builds
+/// check out from the mirror and only fetch when the requested revision is
+/// not in it yet.
+pub struct GitCache {
+    dir: PathBuf,
+}
+
+impl GitCache {
+    pub const fn new(dir: PathBuf) -> Self {
+        Self { dir }
+    }
+
+    /// The cache under `$XDG_CACHE_HOME/flatplay/git`.
+    pub fn user_default() -> Self {
+        Self::new(user_cache_dir().join("flatplay").join("git"))
+    }
+
+    // The readable part of the name is lossy, so URLs that only differ in
+    // punctuation get their own mirror through the hash.
+    fn mirror_path(&self, url: &str) -> PathBuf {
+        let hash: String = Sha256::digest(url.as_bytes())
+            .iter()
+            .take(8)
+            .map(|byte| format!("{byte:02x}"))
+            .collect();
+        let name: String = url
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '.' || c == '-' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        self.dir
+            .join(format!("{}-{hash}.git", name.trim_start_matches('.')))
+    }
+
+    // Held while a mirror is updated, as sources of the same repository, like
+    // submodules shared by several sources, can be fetched at the same time.
+    // Whoever waits finds the revision already fetched.
+    fn lock_mirror(&self, mirror: &Path) -> Result<Flock<File>> {
+        fs::create_dir_all(&self.dir)?;
+        let lock_path = mirror.with_extension("lock");
+        let file = File::options()
+            .create(true)
+            .truncate(false)
+            .write(true)
+            .open(&lock_path)
+            .with_context(|| format!("Failed to open {}", lock_path.display()))?;
+        Flock::lock(file, FlockArg::LockExclusive).map_err(|(_file, error)| {
+            anyhow::anyhow!("Failed to lock {}: {error}", lock_path.display())
+        })
+    }
+
+    /// Resolves `revision` to a commit id in the mirror of `url`, if the mirror
+    /// has it.
+    pub fn resolve(&self, url: &str, revision: &str) -> Result<Option<String>> {
+        let mirror = self.mirror_path(url);
+        if !mirror.is_dir() {
+            return Ok(None);
+        }
+        resolve_commit(&mirror, revision)
+    }
+
+    /// Makes sure the mirror of `url` contains `revision`, fetching it if it
+    /// does not, and returns the commit it resolves to. Branches move, so they
+    /// are always fetched unless offline.
+    pub fn update(
+        &self,
+        url: &str,
+        revision: &str,
+        is_branch: bool,
+        options: &FetchOptions,
+    ) -> Result<String> {
+        let mirror = self.mirror_path(url);
+        let _lock = self.lock_mirror(&mirror)?;
+        let offline = options.offline && !is_local_url(url);
+        // A shallow mirror is not enough when the full history is wanted.
+        let complete = options.shallow || !mirror.join("shallow").exists();
+        if (!is_branch || offline)
+            && complete
+            && let Some(commit) = self.resolve(url, revision)?
+        {
+            verbose(format!("Using cached mirror of {url} ({revision})"));
+            return Ok(commit);
+        }
+        if offline {
+            anyhow::bail!("{url} ({revision}) is not in the git cache");
+        }
+
+        let mirror_str = path_to_str(&mirror)?;
+        if mirror.is_dir() {
+            status(format!("Fetching {url}"));
+        } else {
+            status(format!("Mirroring {url}"));
+            fs::create_dir_all(&mirror)?;
+            run_command("git", &["init", "--bare", "--quiet", mirror_str], None)?;
+            run_command(
+                "git",
+                &["-C", mirror_str, "remote", "add", "origin", url],
+                None,
+            )?;
+            // Branches and tags are mirrored as they are, which leaves
+            // refs/flatplay for the commits that builds asked for.
+            run_command(
+                "git",
+                &[
+                    "-C",
+                    mirror_str,
+                    "config",
+                    "--add",
+                    "remote.origin.fetch",
+                    "+refs/tags/*:refs/tags/*",
+                ],
+                None,
+            )?;
+            run_command(
+                "git",
+                &[
+                    "-C",
+                    mirror_str,
+                    "config",
+                    "--replace-all",
+                    "remote.origin.fetch",
+                    "+refs/heads/*:refs/heads/*",
+                    r"refs/remotes",
+                ],
+                None,
+            )?;
+        }
+        let fsck = if options.fsck_objects {
+            "transfer.fsckObjects=true"
+        } else {
+            "transfer.fsckObjects=false"
+        };
+        let git_fetch = |args: &[&str]| {
+            let mut full_args = vec!["-c", fsck, "-C", mirror_str, "fetch"];
+            full_args.extend_from_slice(args);
+            run_command("git", &full_args, None)
+        };
+
+        if options.shallow && revision != "HEAD" {
+            let refspec = if is_branch {
+                format!("+refs/heads/{revision}:refs/heads/{revision}")
+            } else if is_commit_id(revision) {
+                revision.to_string()
+            } else {
+                format!("+refs/tags/{revision}:refs/tags/{revision}")
+            };
+            match git_fetch(&["--depth", "1", "origin", &refspec]) {
+                Ok(()) => {
+                    if let Some(commit) = resolve_commit(&mirror, revision)? {
+                        return Self::pin(&mirror, commit);
+                    }
+                }
+                Err(error) if is_interrupted_error(&error) => return Err(error),
+                // Not every server allows fetching single commits.
+                Err(_) => verbose(format!(
+                    "Shallow fetch of {url} failed, fetching everything"
+                )),
+            }
+        }
+
+        if mirror.join("shallow").exists() {
+            git_fetch(&["--prune", "--tags", "--unshallow", "origin"])?;
+        } else {
+            git_fetch(&["--prune", "--tags", "origin"])?;
+        }
+        if revision == "HEAD" {
+            Self::update_mirror_head(&mirror)?;
+        }
+        if resolve_commit(&mirror, revision)?.is_none() {
+            // Commits that are not reachable from any ref can often still be
+            // fetched directly.
+            git_fetch(&["origin", revision])?;
+        }
+        let commit = resolve_commit(&mirror, revision)?
+            .ok_or_else(|| anyhow::anyhow!("{url} does not contain {revision}"))?;
+        Self::pin(&mirror, commit)
+    }
+
+    // Keeps a ref to a commit that was asked for, so that it stays in the
+    // mirror and can be cloned from it even when no branch or tag points to it.
+    fn pin(mirror: &Path, commit: String) -> Result<String> {
+        git_query(
+            mirror,
+            &["update-ref", &format!("refs/flatplay/{commit}"), &commit],
+        )?;
+        Ok(commit)
+    }
+
+    // Points the mirror's HEAD at the default branch of the remote.
+    fn update_mirror_head(mirror: &Path) -> Result<()> {
+        let remote_head = git_query(mirror, &["ls-remote", "--symref", "origin", "HEAD"])?;
+        let default_branch = remote_head.lines().find_map(|line| {
+            line.strip_prefix("ref: ")
+                .and_then(|rest| rest.strip_suffix("\tHEAD"))
+        });
+        if let Some(branch) = default_branch {
+            git_query(mirror, &["symbolic-ref", "HEAD", branch])?;
+        }
+        Ok(())
+    }
+
+    /// Makes sure that `tag` of `url` is `commit`, for sources that pin both.
+    pub fn check_tag(
+        &self,
+        url: &str,
+        tag: &str,
+        commit: &str,
+        options: &FetchOptions,
+    ) -> Result<()> {
+        let tag_commit = self.update(url, tag, false, options)?;
+        if tag_commit != commit {
+            anyhow::bail!("Tag {tag} of {url} is commit {tag_commit}, not the expected {commit}");
+        }
+        Ok(())
+    }
+
+    /// Checks out `commit` from the mirror of `url` into `dest` with a local
+    /// clone, then does the same for its submodules from their own mirrors.
+    pub fn checkout(
+        &self,
+        url: &str,
+        commit: &str,
+        dest: &Path,
+        options: &FetchOptions,
+    ) -> Result<()> {
+        let mirror = self.mirror_path(url);
+        if let Some(parent) = dest.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        let dest_str = path_to_str(dest)?;
+        run_command(
+            "git",
+            &[
+                "clone",
+                "--quiet",
+                "--no-checkout",
+                path_to_str(&mirror)?,
+                dest_str,
+            ],
+            None,
+        )?;
+        if resolve_commit(dest, commit)?.is_none() {
+            run_command(
+                "git",
+                &[
+                    "-C",
+                    dest_str,
+                    "fetch",
+                    "--quiet",
+                    "origin",
+                    &format!("refs/flatplay/{commit}"),
+                ],
+                None,
+            )?;
+        }
+        run_command(
+            "git",
+            &["-C", dest_str, "remote", "set-url", "origin", url],
+            None,
+        )?;
+        run_command(
+            "git",
+            &["-C", dest_str, "checkout", "--quiet", "--detach", commit],
+            None,
+        )?;
+        if options.submodules {
+            self.checkout_submodules(dest, options)?;
+        }
+        Ok(())
+    }
+
+    fn checkout_submodules(&self, checkout: &Path, options: &FetchOptions) -> Result<()> {
+        if !checkout.join(".gitmodules").is_file() {
+            return Ok(());
+        }
+        let checkout_str = path_to_str(checkout)?;
+        // Copies the submodule URLs into the config, resolving relative ones.
+        run_command(
+            "git",
+            &["-C", checkout_str, "submodule", "--quiet", "init"],
+            None,
+        )?;
+        let paths = git_query(
+            checkout,
+            &[
+                "config",
+                "--file",
+                ".gitmodules",
+                "--get-regexp",
+                r"^submodule\..*\.path$",
+            ],
+        )?;
+        for line in paths.lines() {
+            let Some((key, path)) = line.split_once(' ') else {
+                continue;
+            };
+            let Some(name) = key
+                .strip_prefix("submodule.")
+                .and_then(|k| k.strip_suffix(".path"))
+            else {
+                continue;
+            };
+            // Skips submodules that are not part of the checked out tree.
+            let tree_entry = git_query(checkout, &["ls-tree", "HEAD", path])?;
+            let mut fields = tree_entry.split_whitespace();
+            let (Some(_mode), Some("commit"), Some(commit)) =
+                (fields.next(), fields.next(), fields.next())
+            else {
+                continue;
+            };
+            let url = git_query(checkout, &["config", &format!("submodule.{name}.url")])?;
+            let url = url.trim();
+            let commit = self.update(url, commit, false, options)?;
+            let dest = safe_join(checkout, path)?;
+            if dest.exists() {
+                fs::remove_dir_all(&dest)?;
+            }
+            self.checkout(url, &commit, &dest, options)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether `url` is a repository on this machine, like the ones of git sources
+/// given by a `path`, which can be fetched from even while offline.
+pub fn is_local_url(url: &str) -> bool {
+    url.starts_with("file://") || Path::new(url).is_absolute()
+}
+
+fn git_query(repo: &Path, args: &[&str]) -> Result<String> {
+    let mut full_args = vec!["-C", path_to_str(repo)?];
+    full_args.extend_from_slice(args);
+    let output = command_output("git", &full_args)?;
+    if !output.status.success() {
+        anyhow::bail!("git {} failed in {}", args.join(" "), repo.display());
+    }
+    String::from_utf8(output.stdout).context("git output is not valid UTF-8")
+}
+
+// Full object ids of SHA-1 and SHA-256 repositories.
+fn is_commit_id(revision: &str) -> bool {
+    matches!(revision.len(), 40 | 64) && revision.bytes().all(|b| b.is_ascii_hexdigit())
+}
+
+fn resolve_commit(repo: &Path, revision: &str) -> Result<Option<String>> {
+    let output = command_output(
+        "git",
+        &[
+            "-C",
+            path_to_str(repo)?,
+            "rev-parse",
+            "--verify",
+            "--quiet",
+            &format!("{revision}^{{commit}}"),
+        ],
+    )?;
+    if !output.status.success() {
+        return Ok(None);
+    }
+    Ok(Some(
+        String::from_utf8_lossy(&output.stdout).trim().to_string(),
+    ))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::process::Command;
+
+    fn git(dir: &Path, args: &[&str]) -> String {
+        let output = Command::new("git")
+            .arg("-C")
+            .arg(dir)
+            .args(["-c", "user.name=Test", "-c", "user.email=test@example.com"])
+            .args(args)
+            .output()
+            .unwrap();
+        assert!(output.status.success(), "git {args:?} failed");
+        String::from_utf8(output.stdout).unwrap().trim().to_string()
+    }
+
+    fn commit_file(repo: &Path, contents: &str) -> String {
+        fs::write(repo.join("file.txt"), contents).unwrap();
+        git(repo, &["add", "file.txt"]);
+        git(repo, &["commit", "--quiet", "-m", contents]);
+        git(repo, &["rev-parse", "HEAD"])
+    }
+
+    #[test]
+    fn test_mirror_fetches_only_missing_revisions() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let upstream = temp_dir.path().join("upstream");
+        fs::create_dir(&upstream).unwrap();
+        git(&upstream, &["init", "--quiet"]);
+        let first = commit_file(&upstream, "one");
+        git(&upstream, &["tag", "v1"]);
+        let url = format!("file://{}", upstream.display());
+
+        let cache = GitCache::new(temp_dir.path().join("cache"));
+        let online = FetchOptions::default();
+        let offline = FetchOptions {
+            offline: true,
+            ..FetchOptions::default()
+        };
+        assert!(cache.resolve(&url, &first).unwrap().is_none());
+        assert_eq!(cache.update(&url, "v1", false, &online).unwrap(), first);
+
+        // Cached revisions resolve without touching the upstream repository.
+        let second = commit_file(&upstream, "two");
+        assert_eq!(cache.update(&url, &first, false, &offline).unwrap(), first);
+        assert!(cache.resolve(&url, &second).unwrap().is_none());
+        assert_eq!(cache.update(&url, &second, false, &online).unwrap(), second);
+
+        let checkout = temp_dir.path().join("checkout");
+        cache.checkout(&url, &first, &checkout, &offline).unwrap();
+        assert_eq!(
+            fs::read_to_string(checkout.join("file.txt")).unwrap(),
+            "one"
+        );
+        assert_eq!(git(&checkout, &["remote", "get-url", "origin"]), url);
+    }
+
+    #[test]
+    fn test_concurrent_updates_of_one_mirror() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let upstream = temp_dir.path().join("upstream");
+        fs::create_dir(&upstream).unwrap();
+        git(&upstream, &["init", "--quiet"]);
+        let commit = commit_file(&upstream, "one");
+        let url = format!("file://{}", upstream.display());
+
+        let cache = GitCache::new(temp_dir.path().join("cache"));
+        let commits: Vec<String> = std::thread::scope(|scope| {
+            let updates: Vec<_> = (0..4)
+                .map(|_| scope.spawn(|| cache.update(&url, "HEAD", true, &FetchOptions::default())))
+                .collect();
+            updates
+                .into_iter()
+                .map(|update| update.join().unwrap().unwrap())
+                .collect()
+        });
+        assert!(commits.iter().all(|c| *c == commit));
+    }
+
+    #[test]
+    fn test_checkout_submodules_from_mirrors() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let library = temp_dir.path().join("library");
+        fs::create_dir(&library).unwrap();
+        git(&library, &["init", "--quiet"]);
+        let library_commit = commit_file(&library, "library");
+        let library_url = format!("file://{}", library.display());
+
+        let app = temp_dir.path().join("app");
+        fs::create_dir(&app).unwrap();
+        git(&app, &["init", "--quiet"]);
+        git(
+            &app,
+            &[
+                "-c",
+                "protocol.file.allow=always",
+                "submodule",
+                "--quiet",
+                "add",
+                &library_url,
+                "subprojects/library",
+            ],
+        );
+        let commit = commit_file(&app, "app");
+        let url = format!("file://{}", app.display());
+
+        let cache = GitCache::new(temp_dir.path().join("cache"));
+        let online = FetchOptions::default();
+        let offline = FetchOptions {
+            offline: true,
+            ..FetchOptions::default()
+        };
+        let checkout = temp_dir.path().join("checkout");
+        cache.update(&url, &commit, false, &online).unwrap();
+        cache.checkout(&url, &commit, &checkout, &online).unwrap();
+        assert_eq!(
+            fs::read_to_string(checkout.join("subprojects/library/file.txt")).unwrap(),
+            "library"
+        );
+        assert_eq!(
+            cache.resolve(&library_url, &library_commit).unwrap(),
+            Some(library_commit)
+        );
+
+        // Later checkouts reuse the submodule mirror.
+        fs::remove_dir_all(&checkout).unwrap();
+        fs::remove_dir_all(&library).unwrap();
+        cache.checkout(&url, &commit, &checkout, &offline).unwrap();
+        assert!(checkout.join("subprojects/library/file.txt").is_file());
+    }
+
+    #[test]
+    fn test_shallow_and_full_history() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let upstream = temp_dir.path().join("upstream");
+        fs::create_dir(&upstream).unwrap();
+        git(&upstream, &["init", "--quiet", "--initial-branch=main"]);
+        let first = commit_file(&upstream, "one");
+        let second = commit_file(&upstream, "two");
+        git(&upstream, &["tag", "v2"]);
+        let url = format!("file://{}", upstream.display());
+
+        let cache = GitCache::new(temp_dir.path().join("cache"));
+        let shallow = FetchOptions::default();
+        let full = FetchOptions {
+            shallow: false,
+            ..FetchOptions::default()
+        };
+        assert_eq!(cache.update(&url, "v2", false, &shallow).unwrap(), second);
+        assert!(cache.mirror_path(&url).join("shallow").exists());
+        assert!(cache.resolve(&url, &first).unwrap().is_none());
+
+        assert_eq!(cache.update(&url, "v2", false, &full).unwrap(), second);
+        assert!(!cache.mirror_path(&url).join("shallow").exists());
+        assert_eq!(cache.resolve(&url, &first).unwrap(), Some(first));
+        assert_eq!(cache.update(&url, "HEAD", true, &shallow).unwrap(), second);
+    }
+
+    #[test]
+    fn test_offline_refuses_remote_repositories() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let cache = GitCache::new(temp_dir.path().join("cache"));
+        let offline = FetchOptions {
+            offline: true,
+            ..FetchOptions::default()
+        };
+        // Nothing can answer at this URL, so only the offline check passes it.
+        let url = "https://flatplay.invalid/repo.git";
+        let error = cache.update(url, "v1", false, &offline).unwrap_err();
+        assert!(error.to_string().contains("is not in the git cache"));
+        assert!(!cache.mirror_path(url).exists());
+    }
+
+    #[test]
+    fn test_is_local_url() {
+        assert!(is_local_url("file:///srv/git/repo"));
+        assert!(is_local_url("/srv/git/repo"));
+        assert!(!is_local_url("https://example.com/repo.git"));
+        assert!(!is_local_url("git@example.com:repo.git"));
+    }
+
+    #[test]
+    fn test_local_repositories_fetch_offline() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let upstream = temp_dir.path().join("upstream");
+        fs::create_dir(&upstream).unwrap();
+        git(&upstream, &["init", "--quiet"]);
+        let first = commit_file(&upstream, "one");
+        let url = format!("file://{}", upstream.display());
+
+        let cache = GitCache::new(temp_dir.path().join("cache"));
+        let offline = FetchOptions {
+            offline: true,
+            ..FetchOptions::default()
+        };
+        assert_eq!(cache.update(&url, &first, false, &offline).unwrap(), first);
+        let second = commit_file(&upstream, "two");
+        assert_eq!(cache.update(&url, "HEAD", true, &offline).unwrap(), second);
+        // Plain paths are local as well.
+        let path_url = upstream.display().to_string();
+        assert_eq!(
+            cache.update(&path_url, &second, false, &offline).unwrap(),
+            second
+        );
+    }
+
+    #[test]
+    fn test_check_tag() {
+        let temp_dir = tempfile::tempdir().unwrap();
+        let upstream = temp_dir.path().join("upstream");
+        fs::create_dir(&upstream).unwrap();
+        git(&upstream, &["init", "--quiet"]);
+        let first = commit_file(&upstream, "one");
+        git(&upstream, &["tag", "v1"]);
+        let second = commit_file(&upstream, "two");
+        let url = format!("file://{}", upstream.display());
+
+        let cache = GitCache::new(temp_dir.path().join("cache"));
+        let options = FetchOptions::default();
+        assert_eq!(
+            cache.update(&url, &second, false, &options).unwrap(),
+            second
+        );
+        let error = cache.check_tag(&url, "v1", &second, &options).unwrap_err();
+        assert!(error.to_string().contains("not the expected"));
+        cache.check_tag(&url, "v1", &first, &options).unwrap();
+    }
+
+    #[test]
+    fn test_is_commit_id() {
+        assert!(is_commit_id(&"a".repeat(40)));
+        assert!(is_commit_id(&"0f".repeat(32)));
+        assert!(!is_commit_id("v1.0"));
+        assert!(!is_commit_id(&"a".repeat(12)));
+        assert!(!is_commit_id(&"g".repeat(40)));
+    }
+
+    #[test]
+    fn test_mirror_path() {
+        let cache = GitCache::new(PathBuf::from("/cache"));
+        let mirror = cache.mirror_path("https://example.com/repo.git");
+        let name = mirror.file_name().unwrap().to_str().unwrap();
+        assert!(name.starts_with("https___example.com_repo.git-"));
+        assert_eq!(mirror, cache.mirror_path("https://example.com/repo.git"));
+        assert_ne!(
+            cache.mirror_path("https://h/a_b/c"),
+            cache.mirror_path("https://h/a/b_c")
+        );
+        assert_eq!(
+            cache.mirror_path("../..").parent(),
+            Some(Path::new("/cache"))
+        );
+    }
+}
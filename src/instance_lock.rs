@@ -23,10 +23,15 @@ struct ProcessMetadata {
     id: u32,
     group_id: u32,
     start_time_ticks: u64,
+    // The process group of an application that watch mode started in its own
+    // group, so that it is stopped along with flatplay.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    app_group_id: Option<u32>,
 }
 
 pub struct InstanceLock {
     file: Flock<File>,
+    process_group_id: u32,
 }
 
 impl InstanceLock {
@@ -51,8 +56,11 @@ impl InstanceLock {
 
         match Flock::lock(file, FlockArg::LockExclusiveNonblock) {
             Ok(file) => {
-                let mut lock = Self { file };
-                lock.write_current_metadata(process_group_id)?;
+                let mut lock = Self {
+                    file,
+                    process_group_id,
+                };
+                lock.write_metadata(None)?;
                 Ok(lock)
             }
             Err((file, Errno::EWOULDBLOCK)) => {
@@ -64,8 +72,11 @@ impl InstanceLock {
                 loop {
                     match Flock::lock(unlocked_file, FlockArg::LockExclusiveNonblock) {
                         Ok(file) => {
-                            let mut lock = Self { file };
-                            lock.write_current_metadata(process_group_id)?;
+                            let mut lock = Self {
+                                file,
+                                process_group_id,
+                            };
+                            lock.write_metadata(None)?;
                             return Ok(lock);
                         }
                         Err((file, Errno::EWOULDBLOCK)) => {
@@ -90,12 +101,19 @@ impl InstanceLock {
         }
     }
 
-    fn write_current_metadata(&mut self, process_group_id: u32) -> Result<()> {
+    /// Records the process group of an application running outside of
+    /// flatplay's own group, or clears it with `None`.
+    pub fn set_app_process_group(&mut self, app_group_id: Option<u32>) -> Result<()> {
+        self.write_metadata(app_group_id)
+    }
+
+    fn write_metadata(&mut self, app_group_id: Option<u32>) -> Result<()> {
         let process_id = std::process::id();
         let metadata = ProcessMetadata {
             id: process_id,
-            group_id: process_group_id,
+            group_id: self.process_group_id,
             start_time_ticks: process_start_time_ticks(process_id)?,
+            app_group_id,
         };
 
         let file = &mut *self.file;
@@ -130,7 +148,16 @@ pub fn request_shutdown_from_lock(base_dir: &Path) -> Result<()> {
     }
 
     let process_group = Pid::from_raw(previous_process.group_id.cast_signed());
-    match killpg(process_group, Signal::SIGTERM) {
+    let result = killpg(process_group, Signal::SIGTERM);
+    if let Some(app_group_id) = previous_process.app_group_id {
+        match killpg(Pid::from_raw(app_group_id.cast_signed()), Signal::SIGTERM) {
+            Ok(()) | Err(Errno::ESRCH) => {}
+            Err(error) => status_warn(format!(
+                "Failed to stop the application (PGID: {app_group_id}): {error}"
+            )),
+        }
+    }
+    match result {
         Ok(()) => {
             status_success(format!(
                 "Successfully stopped flatplay process group (PGID: {})",
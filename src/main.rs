@@ -5,15 +5,25 @@ use std::path::PathBuf;
 use std::process::{Command, ExitCode, Stdio};
 use std::sync::atomic::{AtomicBool, Ordering};
 
+mod archive;
 mod build_dirs;
+mod checksum;
 mod command;
+mod compile_commands;
+mod configure_stamp;
+mod download;
+mod download_cache;
 mod flatpak_manager;
+mod git_cache;
 mod instance_lock;
 mod manifest;
+mod profile;
+mod runtimes;
 mod state;
 mod utils;
+mod watch;
 
-use flatpak_manager::FlatpakManager;
+use flatpak_manager::{FlatpakManager, Options};
 use instance_lock::{InstanceLock, request_shutdown_from_lock};
 use state::State;
 use utils::verbose;
@@ -31,6 +41,30 @@ struct Cli {
     #[arg(short, long, global = true)]
     verbose: bool,
 
+    /// Install a missing SDK, runtime or SDK extension without asking
+    #[arg(long, global = true)]
+    install_missing: bool,
+
+    /// Flatpak remote to install missing runtimes from
+    #[arg(long, global = true, default_value = "flathub")]
+    remote: String,
+
+    /// Never access the network, only use cached downloads and existing checkouts
+    #[arg(long, global = true)]
+    offline: bool,
+
+    /// Number of sources to download at the same time
+    #[arg(long, global = true, default_value_t = 4, value_parser = clap::value_parser!(u16).range(1..))]
+    download_jobs: u16,
+
+    /// Build profile: debug, release, asan, ubsan or one from x-flatplay-profiles
+    #[arg(long, global = true, value_name = "NAME")]
+    profile: Option<String>,
+
+    /// Build a dependency module from its local sources like the application (repeatable)
+    #[arg(long = "module", global = true, value_name = "NAME")]
+    modules: Vec<String>,
+
     #[command(subcommand)]
     command: Option<Commands>,
 }
@@ -43,6 +77,19 @@ enum Commands {
     BuildAndRun,
     /// Clean the Flatpak repo directory and rebuild the application
     Rebuild,
+    /// Rebuild a single module, along with the dependencies after it
+    RebuildModule {
+        /// Name of the module to rebuild
+        name: String,
+    },
+    /// Build the application, then run its test suite
+    Test {
+        /// Give the tests access to the display, for UI tests
+        #[arg(long)]
+        display: bool,
+    },
+    /// Build and run the application, rebuilding and restarting it when its sources change
+    Watch,
     /// Stop the currently running task
     Stop,
     /// Run the application
@@ -130,7 +177,7 @@ fn check_dependencies() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn run(command: Option<&Commands>) -> anyhow::Result<()> {
+fn run(command: Option<&Commands>, options: Options) -> anyhow::Result<()> {
     ctrlc::set_handler(|| {
         INTERRUPTED.store(true, Ordering::SeqCst);
     })?;
@@ -163,7 +210,7 @@ fn run(command: Option<&Commands>) -> anyhow::Result<()> {
         return flatpak_manager.clean();
     }
 
-    let mut flatpak_manager = FlatpakManager::new(&mut state);
+    let mut flatpak_manager = FlatpakManager::new(&mut state).with_options(options);
     flatpak_manager.validate_manifest(command.is_none())?;
 
     let pid = getpid();
@@ -171,7 +218,7 @@ fn run(command: Option<&Commands>) -> anyhow::Result<()> {
         .map_err(|error| anyhow::anyhow!("Failed to set process group ID: {error}"))?;
     let process_group_id = pid.as_raw().cast_unsigned();
 
-    let _instance_lock = InstanceLock::acquire_or_takeover(&base_dir, process_group_id)?;
+    let mut instance_lock = InstanceLock::acquire_or_takeover(&base_dir, process_group_id)?;
 
     flatpak_manager.ensure_ready(command.is_none())?;
 
@@ -179,7 +226,10 @@ fn run(command: Option<&Commands>) -> anyhow::Result<()> {
         None | Some(Commands::BuildAndRun) => flatpak_manager.build_and_run(),
         Some(Commands::Build) => flatpak_manager.build(),
         Some(Commands::Rebuild) => flatpak_manager.rebuild(),
+        Some(Commands::RebuildModule { name }) => flatpak_manager.rebuild_module(name),
         Some(Commands::Run) => flatpak_manager.run(),
+        Some(Commands::Test { display }) => flatpak_manager.test(*display),
+        Some(Commands::Watch) => flatpak_manager.watch(&mut instance_lock),
         Some(Commands::UpdateDependencies) => flatpak_manager.update_dependencies(),
         Some(Commands::RuntimeTerminal) => flatpak_manager.runtime_terminal(),
         Some(Commands::BuildTerminal) => flatpak_manager.build_terminal(),
@@ -205,7 +255,15 @@ fn main() -> ExitCode {
             ExitCode::SUCCESS
         }
         command => {
-            if let Err(error) = run(command.as_ref()) {
+            let options = Options {
+                install_missing: cli.install_missing,
+                remote: cli.remote,
+                offline: cli.offline,
+                download_jobs: cli.download_jobs.into(),
+                devel_modules: cli.modules,
+                profile: cli.profile,
+            };
+            if let Err(error) = run(command.as_ref(), options) {
                 // Check if this was an intentional interruption (Ctrl+C)
                 if crate::command::is_interrupted_error(&error) {
                     eprintln!();
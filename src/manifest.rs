@@ -5,6 +5,16 @@ use std::path::{Path, PathBuf};
 
 use anyhow::{Context, Result};
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+
+use crate::profile::Profile;
+
+fn sha256_hex(data: &[u8]) -> String {
+    Sha256::digest(data)
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
 
 pub fn is_valid_dbus_name(name: &str) -> bool {
     if name.is_empty() || name.len() > 255 {
@@ -64,10 +74,86 @@ pub enum Module {
         post_install: Option<Vec<String>>,
         #[serde(default)]
         sources: Vec<serde_json::Value>,
+        #[serde(default)]
+        x_flatplay_cargo: Option<bool>,
+        #[serde(default)]
+        x_flatplay_devel: Option<bool>,
+        #[serde(default)]
+        x_test_commands: Option<Vec<String>>,
+        #[serde(default)]
+        modules: Vec<Self>,
+        // Everything flatplay does not use itself, kept so that module hashes
+        // change along with it.
+        #[serde(flatten)]
+        other: serde_json::Map<String, serde_json::Value>,
     },
     Reference(String),
 }
 
+impl Module {
+    pub fn name(&self) -> Option<&str> {
+        match self {
+            Self::Object { name, .. } => Some(name),
+            Self::Reference(_) => None,
+        }
+    }
+
+    /// Whether this is a Rust module built with cargo. `x-flatplay-cargo` takes
+    /// precedence, otherwise `simple` modules whose build commands invoke cargo qualify.
+    pub fn is_cargo(&self) -> bool {
+        match self {
+            Self::Object {
+                buildsystem,
+                build_commands,
+                x_flatplay_cargo,
+                ..
+            } => x_flatplay_cargo.unwrap_or_else(|| {
+                buildsystem.as_deref() == Some("simple")
+                    && build_commands.iter().flatten().any(|command| {
+                        command
+                            .split_whitespace()
+                            .any(|word| Path::new(word).file_name().is_some_and(|n| n == "cargo"))
+                    })
+            }),
+            Self::Reference(_) => false,
+        }
+    }
+
+    /// Whether `x-flatplay-devel` marks this module for being built by flatplay
+    /// from its local sources, like the application module.
+    pub fn is_devel(&self) -> bool {
+        match self {
+            Self::Object {
+                x_flatplay_devel, ..
+            } => x_flatplay_devel.unwrap_or(false),
+            Self::Reference(_) => false,
+        }
+    }
+}
+
+/// A module definition from the flattened module tree, along with the directory
+/// its relative paths (sources, nested references) are resolved against.
+#[derive(Debug, Clone)]
+pub struct ResolvedModule {
+    pub module: Module,
+    pub base_dir: PathBuf,
+}
+
+impl ResolvedModule {
+    pub fn name(&self) -> &str {
+        self.module.name().unwrap_or_default()
+    }
+
+    /// Hash of the module definition, for telling which modules changed.
+    pub fn hash(&self) -> Result<String> {
+        // Going through a `Value` sorts the keys of maps like `env`, whose
+        // order would otherwise differ between runs.
+        Ok(sha256_hex(
+            serde_json::to_value(&self.module)?.to_string().as_bytes(),
+        ))
+    }
+}
+
 #[derive(Serialize, Deserialize, Debug, Clone)]
 #[serde(rename_all = "kebab-case")]
 pub struct Manifest {
@@ -89,6 +175,10 @@ pub struct Manifest {
     pub sdk_extensions: Vec<String>,
     #[serde(default)]
     pub cleanup: Vec<String>,
+    #[serde(default)]
+    pub x_flatplay_profiles: HashMap<String, Profile>,
+    #[serde(flatten)]
+    other: serde_json::Map<String, serde_json::Value>,
 }
 
 impl Manifest {
@@ -105,6 +195,16 @@ impl Manifest {
         Ok(manifest)
     }
 
+    /// Hash of everything in the manifest except its modules. Changing any of
+    /// it affects how every module is built.
+    pub fn settings_hash(&self) -> Result<String> {
+        let mut settings = serde_json::to_value(self)?;
+        if let Some(settings) = settings.as_object_mut() {
+            settings.remove("modules");
+        }
+        Ok(sha256_hex(settings.to_string().as_bytes()))
+    }
+
     pub fn finish_args_filtered(&self) -> Vec<String> {
         self.finish_args
             .iter()
@@ -149,9 +249,18 @@ impl Manifest {
         let m_prepend_pkg = module_build_options.and_then(|b| b.prepend_pkg_config_path.as_deref());
         let m_append_pkg = module_build_options.and_then(|b| b.append_pkg_config_path.as_deref());
 
+        let extension_dirs = self.sdk_extension_dirs();
+        let path_defaults: Vec<String> = ["/app/bin".to_string(), "/usr/bin".to_string()]
+            .into_iter()
+            .chain(extension_dirs.iter().map(|dir| format!("{dir}/bin")))
+            .collect();
+        let ld_library_path_defaults: Vec<String> = std::iter::once("/app/lib".to_string())
+            .chain(extension_dirs.iter().map(|dir| format!("{dir}/lib")))
+            .collect();
+
         if let Some(arg) = Self::build_path_override(
             "PATH",
-            &["/app/bin", "/usr/bin"],
+            &path_defaults.iter().map(String::as_str).collect::<Vec<_>>(),
             self.build_options.prepend_path.as_deref(),
             mpp,
             self.build_options.append_path.as_deref(),
@@ -162,7 +271,10 @@ impl Manifest {
 
         if let Some(arg) = Self::build_path_override(
             "LD_LIBRARY_PATH",
-            &["/app/lib"],
+            &ld_library_path_defaults
+                .iter()
+                .map(String::as_str)
+                .collect::<Vec<_>>(),
             self.build_options.prepend_ld_library_path.as_deref(),
             mlp,
             self.build_options.append_ld_library_path.as_deref(),
@@ -190,45 +302,114 @@ impl Manifest {
         overrides
     }
 
-    pub fn application_module(&self, manifest_path: &Path) -> Result<Module> {
-        match self.modules.last() {
-            Some(module @ Module::Object { .. }) => Ok(module.clone()),
-            Some(Module::Reference(ref_name)) => {
-                let parent = manifest_path
-                    .parent()
-                    .context("Manifest path has no parent directory")?;
-                let ref_path = parent.join(ref_name);
-                let modules = Self::load_module_file(&ref_path)?;
-                match modules.into_iter().last() {
-                    Some(module @ Module::Object { .. }) => Ok(module),
-                    Some(Module::Reference(name)) => Err(anyhow::anyhow!(
-                        "Nested module references not supported: {ref_name} -> {name}"
-                    )),
-                    None => Err(anyhow::anyhow!(
-                        "Referenced module file {ref_name} contains no modules"
-                    )),
-                }
-            }
-            None => Err(anyhow::anyhow!("Manifest has no modules")),
-        }
+    /// Mount points of the SDK extensions inside the build sandbox, e.g.
+    /// `org.freedesktop.Sdk.Extension.rust-stable` is mounted at `/usr/lib/sdk/rust-stable`.
+    pub fn sdk_extension_dirs(&self) -> Vec<String> {
+        self.sdk_extensions
+            .iter()
+            .map(|extension| {
+                let id = extension
+                    .split_once("//")
+                    .map_or(extension.as_str(), |(id, _)| id);
+                let name = id
+                    .rsplit_once(".Extension.")
+                    .map_or_else(|| id.rsplit('.').next().unwrap_or(id), |(_, name)| name);
+                format!("/usr/lib/sdk/{name}")
+            })
+            .collect()
     }
 
-    pub fn last_module_name(&self, manifest_path: &Path) -> Result<String> {
-        match self.modules.last() {
-            Some(Module::Object { name, .. }) => Ok(name.clone()),
-            Some(Module::Reference(ref_name)) => {
-                let parent = manifest_path
-                    .parent()
-                    .context("Manifest path has no parent directory")?;
-                let ref_path = parent.join(ref_name);
-                let modules = Self::load_module_file(&ref_path)?;
-                match modules.into_iter().last() {
-                    Some(Module::Object { name, .. }) => Ok(name),
-                    _ => Ok(ref_name.clone()),
+    /// Resolves module references recursively and flattens the module tree into
+    /// build order: nested `modules` come before the module that declares them,
+    /// just like flatpak-builder builds them.
+    pub fn resolve_modules(&self, manifest_path: &Path) -> Result<Vec<ResolvedModule>> {
+        Ok(self.resolve_modules_from_files(manifest_path)?.0)
+    }
+
+    /// The canonical paths of the module files the manifest includes, directly
+    /// or through other module files.
+    pub fn module_files(&self, manifest_path: &Path) -> Result<Vec<PathBuf>> {
+        Ok(self.resolve_modules_from_files(manifest_path)?.1)
+    }
+
+    fn resolve_modules_from_files(
+        &self,
+        manifest_path: &Path,
+    ) -> Result<(Vec<ResolvedModule>, Vec<PathBuf>)> {
+        let base_dir = manifest_path
+            .parent()
+            .context("Manifest path has no parent directory")?;
+        let mut include_stack = vec![
+            manifest_path
+                .canonicalize()
+                .unwrap_or_else(|_| manifest_path.to_path_buf()),
+        ];
+        let mut resolved = Vec::new();
+        let mut files = Vec::new();
+        Self::resolve_module_list(
+            &self.modules,
+            base_dir,
+            &mut include_stack,
+            &mut resolved,
+            &mut files,
+        )?;
+        Ok((resolved, files))
+    }
+
+    fn resolve_module_list(
+        modules: &[Module],
+        base_dir: &Path,
+        include_stack: &mut Vec<PathBuf>,
+        resolved: &mut Vec<ResolvedModule>,
+        files: &mut Vec<PathBuf>,
+    ) -> Result<()> {
+        for module in modules {
+            match module {
+                Module::Object {
+                    modules: children, ..
+                } => {
+                    Self::resolve_module_list(children, base_dir, include_stack, resolved, files)?;
+                    resolved.push(ResolvedModule {
+                        module: module.clone(),
+                        base_dir: base_dir.to_path_buf(),
+                    });
+                }
+                Module::Reference(ref_name) => {
+                    let ref_path = base_dir.join(ref_name);
+                    let canonical = ref_path.canonicalize().with_context(|| {
+                        format!("Failed to resolve module file {}", ref_path.display())
+                    })?;
+                    if include_stack.contains(&canonical) {
+                        let chain: Vec<String> = include_stack
+                            .iter()
+                            .chain(std::iter::once(&canonical))
+                            .map(|path| path.display().to_string())
+                            .collect();
+                        return Err(anyhow::anyhow!(
+                            "Cyclic module reference: {}",
+                            chain.join(" -> ")
+                        ));
+                    }
+                    let ref_dir = ref_path
+                        .parent()
+                        .context("Module file path has no parent directory")?;
+                    let ref_modules = Self::load_module_file(&ref_path)?;
+                    if !files.contains(&canonical) {
+                        files.push(canonical.clone());
+                    }
+                    include_stack.push(canonical);
+                    Self::resolve_module_list(
+                        &ref_modules,
+                        ref_dir,
+                        include_stack,
+                        resolved,
+                        files,
+                    )?;
+                    include_stack.pop();
                 }
             }
-            None => Err(anyhow::anyhow!("Manifest has no modules")),
         }
+        Ok(())
     }
 
     fn load_module_file(path: &Path) -> Result<Vec<Module>> {
@@ -440,6 +621,249 @@ finish-args:
         assert_eq!(manifest.command, "test-app");
     }
 
+    #[test]
+    fn test_module_is_cargo() {
+        let module = |value: serde_json::Value| serde_json::from_value::<Module>(value).unwrap();
+
+        assert!(
+            module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "simple",
+                "build-commands": ["cargo --offline build --release"]
+            }))
+            .is_cargo()
+        );
+        assert!(
+            module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "simple",
+                "build-commands": ["/usr/lib/sdk/rust-stable/bin/cargo build"]
+            }))
+            .is_cargo()
+        );
+        assert!(
+            !module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "simple",
+                "build-commands": ["make install"]
+            }))
+            .is_cargo()
+        );
+        assert!(
+            !module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "meson",
+                "build-commands": ["cargo build"]
+            }))
+            .is_cargo()
+        );
+        assert!(
+            module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "simple",
+                "build-commands": ["./build.sh"],
+                "x-flatplay-cargo": true
+            }))
+            .is_cargo()
+        );
+        assert!(
+            !module(serde_json::json!({
+                "name": "app",
+                "buildsystem": "simple",
+                "build-commands": ["cargo build"],
+                "x-flatplay-cargo": false
+            }))
+            .is_cargo()
+        );
+        assert!(!Module::Reference("app.json".to_string()).is_cargo());
+    }
+
+    #[test]
+    fn test_hashes_cover_unknown_keys() {
+        let manifest = |cleanup_commands: &str, make_args: &str| {
+            let manifest: Manifest = serde_json::from_value(serde_json::json!({
+                "id": "org.example.App",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "48",
+                "command": "app",
+                "cleanup-commands": [cleanup_commands],
+                "modules": [{ "name": "app", "make-args": [make_args] }]
+            }))
+            .unwrap();
+            let path = Path::new("/tmp/org.example.App.json");
+            let module = manifest.resolve_modules(path).unwrap().pop().unwrap();
+            (manifest.settings_hash().unwrap(), module.hash().unwrap())
+        };
+
+        let (settings, module) = manifest("true", "V=1");
+        let (other_settings, other_module) = manifest("false", "V=1");
+        assert_ne!(settings, other_settings);
+        assert_eq!(module, other_module);
+        let (other_settings, other_module) = manifest("true", "V=0");
+        assert_eq!(settings, other_settings);
+        assert_ne!(module, other_module);
+    }
+
+    #[test]
+    fn test_module_hash_is_stable() {
+        let module = || {
+            let module: Module = serde_json::from_value(serde_json::json!({
+                "name": "app",
+                "build-options": {
+                    "env": {
+                        "A": "1", "B": "2", "C": "3", "D": "4",
+                        "E": "5", "F": "6", "G": "7", "H": "8"
+                    }
+                }
+            }))
+            .unwrap();
+            ResolvedModule {
+                module,
+                base_dir: PathBuf::from("/tmp"),
+            }
+        };
+        let hash = module().hash().unwrap();
+        for _ in 0..8 {
+            assert_eq!(module().hash().unwrap(), hash);
+        }
+    }
+
+    #[test]
+    fn test_module_is_devel() {
+        let module = |value: serde_json::Value| serde_json::from_value::<Module>(value).unwrap();
+
+        assert!(
+            module(serde_json::json!({
+                "name": "libfoo",
+                "buildsystem": "meson",
+                "x-flatplay-devel": true
+            }))
+            .is_devel()
+        );
+        assert!(!module(serde_json::json!({ "name": "libfoo" })).is_devel());
+        assert!(!Module::Reference("libfoo.json".to_string()).is_devel());
+    }
+
+    #[test]
+    fn test_sdk_extension_paths() {
+        let manifest: Manifest = serde_json::from_value(serde_json::json!({
+            "app-id": "org.example.TestApp",
+            "sdk": "org.gnome.Sdk",
+            "runtime": "org.gnome.Platform",
+            "runtime-version": "47",
+            "command": "test-app",
+            "sdk-extensions": [
+                "org.freedesktop.Sdk.Extension.rust-stable",
+                "org.freedesktop.Sdk.Extension.node20//24.08"
+            ]
+        }))
+        .unwrap();
+
+        assert_eq!(
+            manifest.sdk_extension_dirs(),
+            ["/usr/lib/sdk/rust-stable", "/usr/lib/sdk/node20"]
+        );
+
+        let overrides = manifest.path_overrides(None);
+        let path = overrides
+            .iter()
+            .find(|arg| arg.starts_with("--env=PATH="))
+            .unwrap();
+        assert!(
+            path.ends_with(
+                "/app/bin:/usr/bin:/usr/lib/sdk/rust-stable/bin:/usr/lib/sdk/node20/bin"
+            )
+        );
+        let ld_library_path = overrides
+            .iter()
+            .find(|arg| arg.starts_with("--env=LD_LIBRARY_PATH="))
+            .unwrap();
+        assert!(
+            ld_library_path
+                .ends_with("/app/lib:/usr/lib/sdk/rust-stable/lib:/usr/lib/sdk/node20/lib")
+        );
+    }
+
+    #[test]
+    fn test_resolve_modules_nested() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::create_dir_all(root.join("modules/extra")).unwrap();
+        fs::write(
+            root.join("manifest.json"),
+            r#"{
+                "app-id": "org.example.TestApp",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "47",
+                "command": "test-app",
+                "modules": [
+                    "modules/deps.json",
+                    {
+                        "name": "app",
+                        "modules": [{ "name": "app-child" }]
+                    }
+                ]
+            }"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("modules/deps.json"),
+            r#"[{ "name": "first" }, "extra/more.yaml"]"#,
+        )
+        .unwrap();
+        fs::write(
+            root.join("modules/extra/more.yaml"),
+            "name: second\nmodules:\n  - name: second-child\n",
+        )
+        .unwrap();
+
+        let manifest_path = root.join("manifest.json");
+        let manifest = Manifest::from_file(&manifest_path).unwrap();
+        let modules = manifest.resolve_modules(&manifest_path).unwrap();
+        let names: Vec<&str> = modules.iter().map(ResolvedModule::name).collect();
+        assert_eq!(
+            names,
+            ["first", "second-child", "second", "app-child", "app"]
+        );
+        assert_eq!(modules[0].base_dir, root.join("modules"));
+        assert_eq!(modules[2].base_dir, root.join("modules/extra"));
+        assert_eq!(modules[4].base_dir, root);
+        assert_eq!(
+            manifest.module_files(&manifest_path).unwrap(),
+            [
+                root.join("modules/deps.json").canonicalize().unwrap(),
+                root.join("modules/extra/more.yaml").canonicalize().unwrap()
+            ]
+        );
+    }
+
+    #[test]
+    fn test_resolve_modules_cycle() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path();
+        fs::write(
+            root.join("manifest.json"),
+            r#"{
+                "app-id": "org.example.TestApp",
+                "sdk": "org.gnome.Sdk",
+                "runtime": "org.gnome.Platform",
+                "runtime-version": "47",
+                "command": "test-app",
+                "modules": ["a.json"]
+            }"#,
+        )
+        .unwrap();
+        fs::write(root.join("a.json"), r#"["b.json"]"#).unwrap();
+        fs::write(root.join("b.json"), r#"["a.json"]"#).unwrap();
+
+        let manifest_path = root.join("manifest.json");
+        let manifest = Manifest::from_file(&manifest_path).unwrap();
+        let error = manifest.resolve_modules(&manifest_path).unwrap_err();
+        assert!(error.to_string().contains("Cyclic module reference"));
+    }
+
     #[test]
     fn test_manifest_invalid_app_id() {
         use std::io::Write;
@@ -0,0 +1,234 @@
+use std::collections::HashMap;
+
+use serde::{Deserialize, Serialize};
+
+/// The profiles that are available without defining them in the manifest.
+pub const BUILTIN: [&str; 4] = ["debug", "release", "asan", "ubsan"];
+
+/// How a build is configured on top of the manifest's options. Besides the
+/// built-in ones, manifests can define profiles in `x-flatplay-profiles`.
+#[derive(Serialize, Deserialize, Debug, Clone, Default, PartialEq, Eq)]
+#[serde(rename_all = "kebab-case")]
+pub struct Profile {
+    /// A meson build type, which other build systems get the closest match of.
+    pub buildtype: Option<String>,
+    /// Sanitizers to build with, like meson's `b_sanitize` (`address,undefined`).
+    pub sanitize: Option<String>,
+    /// Extra flags for the C and C++ compilers.
+    pub cflags: Option<String>,
+    #[serde(default)]
+    pub config_opts: Vec<String>,
+    #[serde(default)]
+    pub env: HashMap<String, String>,
+}
+
+impl Profile {
+    pub fn builtin(name: &str) -> Option<Self> {
+        let (buildtype, sanitize) = match name {
+            "debug" => ("debug", None),
+            "release" => ("release", None),
+            "asan" => ("debug", Some("address")),
+            "ubsan" => ("debug", Some("undefined")),
+            _ => return None,
+        };
+        Some(Self {
+            buildtype: Some(buildtype.to_string()),
+            sanitize: sanitize.map(str::to_string),
+            ..Self::default()
+        })
+    }
+
+    fn cmake_build_type(&self) -> Option<&'static str> {
+        match self.buildtype.as_deref()? {
+            "debug" => Some("Debug"),
+            "debugoptimized" => Some("RelWithDebInfo"),
+            "release" => Some("Release"),
+            "minsize" => Some("MinSizeRel"),
+            _ => None,
+        }
+    }
+
+    fn optimization_flags(&self) -> Option<&'static str> {
+        match self.buildtype.as_deref()? {
+            "debug" => Some("-O0 -g"),
+            "debugoptimized" => Some("-O2 -g"),
+            "release" => Some("-O3"),
+            "minsize" => Some("-Os"),
+            _ => None,
+        }
+    }
+
+    /// Options for configuring a module with the given build system, to go
+    /// after the manifest's own so that they take precedence.
+    pub fn config_opts(&self, buildsystem: Option<&str>) -> Vec<String> {
+        let mut opts = Vec::new();
+        match buildsystem {
+            Some("meson") => {
+                if let Some(buildtype) = &self.buildtype {
+                    opts.push(format!("-Dbuildtype={buildtype}"));
+                }
+                if let Some(sanitize) = &self.sanitize {
+                    opts.push(format!("-Db_sanitize={sanitize}"));
+                }
+            }
+            Some("cmake" | "cmake-ninja") => {
+                if let Some(build_type) = self.cmake_build_type() {
+                    opts.push(format!("-DCMAKE_BUILD_TYPE={build_type}"));
+                }
+            }
+            _ => {}
+        }
+        opts.extend(self.config_opts.iter().cloned());
+        opts
+    }
+
+    /// Environment for building a module with the given build system. Flags
+    /// are meant to be appended to the ones the manifest sets.
+    pub fn env(&self, buildsystem: Option<&str>) -> Vec<(String, String)> {
+        // meson handles build types and sanitizers itself, cmake build types.
+        let sanitize = match buildsystem {
+            Some("meson") => None,
+            _ => self.sanitize.as_deref(),
+        };
+        let optimization = match buildsystem {
+            Some("meson" | "cmake" | "cmake-ninja") => None,
+            _ => self.optimization_flags(),
+        };
+        let sanitize_flags = sanitize.map(|sanitize| format!("-fsanitize={sanitize}"));
+        let compiler_flags: Vec<&str> = [
+            optimization,
+            sanitize_flags.as_deref(),
+            sanitize.map(|_| "-fno-omit-frame-pointer"),
+            self.cflags.as_deref(),
+        ]
+        .into_iter()
+        .flatten()
+        .collect();
+
+        let mut env = Vec::new();
+        if !compiler_flags.is_empty() {
+            let compiler_flags = compiler_flags.join(" ");
+            env.push(("CFLAGS".to_string(), compiler_flags.clone()));
+            env.push(("CXXFLAGS".to_string(), compiler_flags));
+        }
+        if let Some(sanitize_flags) = sanitize_flags {
+            env.push(("LDFLAGS".to_string(), sanitize_flags));
+        }
+        let mut profile_env: Vec<_> = self.env.clone().into_iter().collect();
+        profile_env.sort();
+        env.extend(profile_env);
+        env
+    }
+}
+
+// The name of an option like `-Dbuildtype=debug` or `--buildtype=debug`.
+fn option_name(opt: &str) -> Option<&str> {
+    let opt = opt.strip_prefix("-D").or_else(|| opt.strip_prefix("--"))?;
+    Some(opt.split_once('=').map_or(opt, |(name, _)| name))
+}
+
+/// Appends profile options to the manifest's, leaving out the manifest
+/// options that the profile sets too. Some build systems, like meson, refuse
+/// an option given twice in different forms.
+pub fn merge_config_opts<'a>(
+    manifest_opts: &[&'a str],
+    profile_opts: &'a [String],
+) -> Vec<&'a str> {
+    let profile_names: Vec<&str> = profile_opts
+        .iter()
+        .filter_map(|opt| option_name(opt))
+        .collect();
+    manifest_opts
+        .iter()
+        .copied()
+        .filter(|opt| option_name(opt).is_none_or(|name| !profile_names.contains(&name)))
+        .chain(profile_opts.iter().map(String::as_str))
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_builtin_profiles() {
+        for name in BUILTIN {
+            assert!(Profile::builtin(name).is_some());
+        }
+        assert_eq!(Profile::builtin("fast"), None);
+
+        let asan = Profile::builtin("asan").unwrap();
+        assert_eq!(
+            asan.config_opts(Some("meson")),
+            ["-Dbuildtype=debug", "-Db_sanitize=address"]
+        );
+        assert!(asan.env(Some("meson")).is_empty());
+        assert_eq!(
+            asan.config_opts(Some("cmake-ninja")),
+            ["-DCMAKE_BUILD_TYPE=Debug"]
+        );
+        assert_eq!(
+            asan.env(Some("cmake")),
+            [
+                (
+                    "CFLAGS".to_string(),
+                    "-fsanitize=address -fno-omit-frame-pointer".to_string()
+                ),
+                (
+                    "CXXFLAGS".to_string(),
+                    "-fsanitize=address -fno-omit-frame-pointer".to_string()
+                ),
+                ("LDFLAGS".to_string(), "-fsanitize=address".to_string()),
+            ]
+        );
+
+        let release = Profile::builtin("release").unwrap();
+        assert!(release.config_opts(None).is_empty());
+        assert_eq!(
+            release.env(None),
+            [
+                ("CFLAGS".to_string(), "-O3".to_string()),
+                ("CXXFLAGS".to_string(), "-O3".to_string()),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_merge_config_opts() {
+        let profile_opts = Profile::builtin("asan").unwrap().config_opts(Some("meson"));
+        assert_eq!(
+            merge_config_opts(
+                &["--buildtype=release", "-Dtests=false", "-Db_sanitize=none"],
+                &profile_opts
+            ),
+            ["-Dtests=false", "-Dbuildtype=debug", "-Db_sanitize=address"]
+        );
+        assert_eq!(
+            merge_config_opts(&["-Dtests=false"], &[]),
+            ["-Dtests=false"]
+        );
+    }
+
+    #[test]
+    fn test_project_profile() {
+        let profile: Profile = serde_json::from_value(serde_json::json!({
+            "buildtype": "debugoptimized",
+            "cflags": "-DPROFILING",
+            "config-opts": ["-Dtracing=true"],
+            "env": { "G_MESSAGES_DEBUG": "all" }
+        }))
+        .unwrap();
+        assert_eq!(
+            profile.config_opts(Some("meson")),
+            ["-Dbuildtype=debugoptimized", "-Dtracing=true"]
+        );
+        assert_eq!(
+            profile.env(Some("meson")),
+            [
+                ("CFLAGS".to_string(), "-DPROFILING".to_string()),
+                ("CXXFLAGS".to_string(), "-DPROFILING".to_string()),
+                ("G_MESSAGES_DEBUG".to_string(), "all".to_string()),
+            ]
+        );
+    }
+}
@@ -0,0 +1,270 @@
+use std::path::PathBuf;
+use std::process::Output;
+
+use anyhow::{Context, Result};
+
+use crate::command::command_output;
+use crate::manifest::Manifest;
+
+// Runs `flatpak info` with the given arguments. Taken as a parameter so that
+// the checks can run against something other than the real installations.
+type Info<'a> = &'a dyn Fn(&[&str]) -> Result<Output>;
+
+fn flatpak_info(args: &[&str]) -> Result<Output> {
+    let mut info_args = vec!["info"];
+    info_args.extend_from_slice(args);
+    command_output("flatpak", &info_args)
+}
+
+fn is_installed(info: Info, flatpak_ref: &str) -> Result<bool> {
+    let output = info(&["--show-ref", flatpak_ref])?;
+    Ok(output.status.success())
+}
+
+fn show_metadata(info: Info, flatpak_ref: &str) -> Result<String> {
+    let output = info(&["--show-metadata", flatpak_ref])?;
+    if !output.status.success() {
+        anyhow::bail!("Failed to read metadata of {flatpak_ref}");
+    }
+    String::from_utf8(output.stdout).context("Runtime metadata is not valid UTF-8")
+}
+
+fn show_location(info: Info, flatpak_ref: &str) -> Result<PathBuf> {
+    let output = info(&["--show-location", flatpak_ref])?;
+    if !output.status.success() {
+        anyhow::bail!("Failed to find where {flatpak_ref} is installed");
+    }
+    let location = String::from_utf8(output.stdout).context("Location is not valid UTF-8")?;
+    Ok(PathBuf::from(location.trim()))
+}
+
+/// Finds the branch of an SDK extension from the `[Extension ...]` groups in
+/// the SDK's metadata, the same way flatpak-builder picks it.
+fn extension_branch<'a>(sdk_metadata: &'a str, extension_id: &str) -> Option<&'a str> {
+    let mut in_matching_group = false;
+    let mut versions = None;
+    for line in sdk_metadata.lines().map(str::trim) {
+        if let Some(group) = line.strip_prefix('[').and_then(|l| l.strip_suffix(']')) {
+            in_matching_group = group.strip_prefix("Extension ").is_some_and(|point| {
+                extension_id == point
+                    || extension_id
+                        .strip_prefix(point)
+                        .is_some_and(|rest| rest.starts_with('.'))
+            });
+            continue;
+        }
+        if !in_matching_group {
+            continue;
+        }
+        match line.split_once('=') {
+            Some(("version", version)) => return Some(version.trim()),
+            Some(("versions", list)) => {
+                versions =
+                    versions.or_else(|| list.split(';').map(str::trim).find(|v| !v.is_empty()));
+            }
+            _ => {}
+        }
+    }
+    versions
+}
+
+/// Installs the SDK, runtime and SDK extensions the manifest needs that are
+/// missing with `install`, which gets refs as `id//branch`.
+pub fn install_missing(
+    manifest: &Manifest,
+    install: &mut dyn FnMut(&[String]) -> Result<()>,
+) -> Result<()> {
+    install_missing_with(manifest, &flatpak_info, install)
+}
+
+fn install_missing_with(
+    manifest: &Manifest,
+    info: Info,
+    install: &mut dyn FnMut(&[String]) -> Result<()>,
+) -> Result<()> {
+    let missing = missing_refs(manifest, info)?;
+    if missing.is_empty() {
+        return Ok(());
+    }
+    install(&missing)?;
+
+    // SDK extension branches are only known once the SDK itself is installed.
+    let missing_extensions = missing_refs(manifest, info)?;
+    if !missing_extensions.is_empty() {
+        install(&missing_extensions)?;
+    }
+    Ok(())
+}
+
+// The SDK, runtime and SDK extension refs (as `id//branch`) needed by the
+// manifest that are not installed. Extensions can only be resolved once the
+// SDK is installed, so this has to be checked again after installing it.
+fn missing_refs(manifest: &Manifest, info: Info) -> Result<Vec<String>> {
+    let sdk_ref = format!("{}//{}", manifest.sdk, manifest.runtime_version);
+    let runtime_ref = format!("{}//{}", manifest.runtime, manifest.runtime_version);
+
+    let mut missing = Vec::new();
+    for flatpak_ref in [&sdk_ref, &runtime_ref] {
+        if !missing.contains(flatpak_ref) && !is_installed(info, flatpak_ref)? {
+            missing.push(flatpak_ref.clone());
+        }
+    }
+
+    if manifest.sdk_extensions.is_empty() {
+        return Ok(missing);
+    }
+    let sdk_metadata = if missing.contains(&sdk_ref) {
+        None
+    } else {
+        Some(show_metadata(info, &sdk_ref)?)
+    };
+    for extension in &manifest.sdk_extensions {
+        let extension_ref = if extension.contains("//") {
+            extension.clone()
+        } else if let Some(metadata) = &sdk_metadata {
+            extension_ref(manifest, metadata, extension)
+        } else {
+            continue;
+        };
+        if !is_installed(info, &extension_ref)? {
+            missing.push(extension_ref);
+        }
+    }
+    Ok(missing)
+}
+
+fn extension_ref(manifest: &Manifest, sdk_metadata: &str, extension: &str) -> String {
+    if extension.contains("//") {
+        return extension.to_string();
+    }
+    let branch =
+        extension_branch(sdk_metadata, extension).unwrap_or(manifest.runtime_version.as_str());
+    format!("{extension}//{branch}")
+}
+
+/// Returns where the SDK and its extensions are mounted in the build sandbox,
+/// along with their `files` directories on the host, extensions first.
+pub fn sdk_mounts(manifest: &Manifest) -> Result<Vec<(String, PathBuf)>> {
+    let info: Info = &flatpak_info;
+    let sdk_ref = format!("{}//{}", manifest.sdk, manifest.runtime_version);
+    let mut mounts = Vec::new();
+    if !manifest.sdk_extensions.is_empty() {
+        let sdk_metadata = show_metadata(info, &sdk_ref)?;
+        for (extension, dir) in manifest
+            .sdk_extensions
+            .iter()
+            .zip(manifest.sdk_extension_dirs())
+        {
+            let extension_ref = extension_ref(manifest, &sdk_metadata, extension);
+            mounts.push((dir, show_location(info, &extension_ref)?.join("files")));
+        }
+    }
+    mounts.push((
+        "/usr".to_string(),
+        show_location(info, &sdk_ref)?.join("files"),
+    ));
+    Ok(mounts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SDK_METADATA: &str = "[Runtime]
+name=org.gnome.Sdk
+runtime=org.gnome.Sdk/x86_64/47
+
+[Extension org.freedesktop.Platform.GL]
+directory=lib/GL
+version=1.4
+
+[Extension org.freedesktop.Sdk.Extension]
+directory=lib/sdk
+subdirectories=true
+version=24.08
+no-autodownload=true
+
+[Extension org.gnome.Sdk.Docs]
+directory=share/runtime/docs
+versions=47;47beta
+";
+
+    #[test]
+    fn test_extension_branch() {
+        assert_eq!(
+            extension_branch(SDK_METADATA, "org.freedesktop.Sdk.Extension.rust-stable"),
+            Some("24.08")
+        );
+        assert_eq!(
+            extension_branch(SDK_METADATA, "org.gnome.Sdk.Docs"),
+            Some("47")
+        );
+        assert_eq!(
+            extension_branch(SDK_METADATA, "org.freedesktop.Sdk.ExtensionFoo"),
+            None
+        );
+        assert_eq!(extension_branch(SDK_METADATA, "org.example.Other"), None);
+    }
+
+    #[test]
+    fn test_install_missing() {
+        use std::cell::RefCell;
+        use std::os::unix::process::ExitStatusExt;
+        use std::process::ExitStatus;
+
+        let manifest: Manifest = serde_json::from_value(serde_json::json!({
+            "id": "org.example.App",
+            "sdk": "org.gnome.Sdk",
+            "runtime": "org.gnome.Platform",
+            "runtime-version": "47",
+            "sdk-extensions": [
+                "org.freedesktop.Sdk.Extension.rust-stable",
+                "org.freedesktop.Sdk.Extension.llvm19//24.08"
+            ],
+            "command": "app",
+            "modules": []
+        }))
+        .unwrap();
+
+        // An installation that only has the LLVM extension so far.
+        let installed = RefCell::new(vec![
+            "org.freedesktop.Sdk.Extension.llvm19//24.08".to_string(),
+        ]);
+        let info = |args: &[&str]| {
+            let flatpak_ref = args[1];
+            let is_installed = installed.borrow().iter().any(|r| r == flatpak_ref);
+            let stdout = match args[0] {
+                "--show-metadata" if is_installed => SDK_METADATA.as_bytes().to_vec(),
+                _ => Vec::new(),
+            };
+            Ok(Output {
+                status: ExitStatus::from_raw(if is_installed { 0 } else { 1 << 8 }),
+                stdout,
+                stderr: Vec::new(),
+            })
+        };
+        let mut installs = Vec::new();
+        install_missing_with(&manifest, &info, &mut |refs| {
+            installs.push(refs.to_vec());
+            installed.borrow_mut().extend(refs.iter().cloned());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(
+            installs,
+            [
+                vec![
+                    "org.gnome.Sdk//47".to_string(),
+                    "org.gnome.Platform//47".to_string()
+                ],
+                vec!["org.freedesktop.Sdk.Extension.rust-stable//24.08".to_string()],
+            ]
+        );
+
+        // Nothing is installed twice.
+        install_missing_with(&manifest, &info, &mut |refs| {
+            panic!("installing {refs:?} again")
+        })
+        .unwrap();
+    }
+}
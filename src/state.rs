@@ -7,13 +7,31 @@ use serde::{Deserialize, Serialize};
 const STATE_DIR: &str = ".flatplay";
 const STATE_FILE_NAME: &str = "state.json";
 
+/// The hash of a module definition, as of the last build.
+#[derive(Serialize, Deserialize, Debug, Clone, PartialEq, Eq)]
+pub struct ModuleHash {
+    pub name: String,
+    pub hash: String,
+}
+
 #[derive(Serialize, Deserialize, Debug)]
 #[serde(default)]
 pub struct State {
     pub active_manifest: Option<PathBuf>,
+    /// The `flatpak build-init` command the build directory was set up with, it
+    /// is set up again when that changes.
+    pub build_init_args: Vec<String>,
+    /// Hash of the manifest without its modules, see `module_hashes` for those.
     pub manifest_hash: Option<String>,
+    pub module_hashes: Vec<ModuleHash>,
+    /// Whether the runtimes the manifest needs were found installed since its
+    /// settings last changed.
+    pub runtimes_checked: bool,
     pub dependencies_updated: bool,
     pub dependencies_built: bool,
+    /// The module flatpak-builder stopped at when the dependencies were last
+    /// built, which moves with the modules built for development.
+    pub dependencies_stop_at: Option<String>,
     pub application_built: bool,
     #[serde(skip)]
     pub base_dir: PathBuf,
@@ -23,9 +41,13 @@ impl Default for State {
     fn default() -> Self {
         Self {
             active_manifest: None,
+            build_init_args: Vec::new(),
             manifest_hash: None,
+            module_hashes: Vec::new(),
+            runtimes_checked: false,
             dependencies_updated: false,
             dependencies_built: false,
+            dependencies_stop_at: None,
             application_built: false,
             base_dir: PathBuf::new(),
         }
@@ -65,10 +87,18 @@ impl State {
     /// Resets the state to its initial values.
     /// This is specifically only for build progress. Not general state.
     pub const fn reset(&mut self) {
+        self.runtimes_checked = false;
         self.dependencies_updated = false;
         self.dependencies_built = false;
         self.application_built = false;
     }
+
+    /// Index of the first module in build order whose definition differs from
+    /// the last build, or that was added or removed since.
+    pub fn first_changed_module(&self, module_hashes: &[ModuleHash]) -> Option<usize> {
+        (0..self.module_hashes.len().max(module_hashes.len()))
+            .find(|&index| self.module_hashes.get(index) != module_hashes.get(index))
+    }
 }
 
 #[cfg(test)]
@@ -91,6 +121,7 @@ mod tests {
         state.active_manifest = Some(PathBuf::from("/tmp/manifest.json"));
         state.manifest_hash = Some("abc123".to_string());
         state.dependencies_updated = true;
+        state.dependencies_stop_at = Some("app".to_string());
 
         state.save().unwrap();
 
@@ -102,19 +133,51 @@ mod tests {
         assert_eq!(loaded_state.manifest_hash, Some("abc123".to_string()));
         assert!(loaded_state.dependencies_updated);
         assert!(!loaded_state.dependencies_built);
+        assert_eq!(loaded_state.dependencies_stop_at.as_deref(), Some("app"));
+    }
+
+    #[test]
+    fn test_first_changed_module() {
+        let hash = |name: &str, hash: &str| ModuleHash {
+            name: name.to_string(),
+            hash: hash.to_string(),
+        };
+        let state = State {
+            module_hashes: vec![hash("libfoo", "1"), hash("libbar", "2"), hash("app", "3")],
+            ..Default::default()
+        };
+
+        let unchanged = state.module_hashes.clone();
+        assert_eq!(state.first_changed_module(&unchanged), None);
+
+        let changed = [hash("libfoo", "1"), hash("libbar", "4"), hash("app", "3")];
+        assert_eq!(state.first_changed_module(&changed), Some(1));
+
+        let inserted = [
+            hash("libfoo", "1"),
+            hash("libbaz", "5"),
+            hash("libbar", "2"),
+        ];
+        assert_eq!(state.first_changed_module(&inserted), Some(1));
+
+        let removed = [hash("libfoo", "1"), hash("libbar", "2")];
+        assert_eq!(state.first_changed_module(&removed), Some(2));
     }
 
     #[test]
+    #[allow(clippy::useless_asref)]
     fn test_state_reset() {
         let temp_dir = tempfile::tempdir().unwrap();
         let mut state = State::load(temp_dir.path().as_ref()).unwrap();
 
+        state.runtimes_checked = true;
         state.dependencies_updated = true;
         state.dependencies_built = true;
         state.application_built = true;
 
         state.reset();
 
+        assert!(!state.runtimes_checked);
         assert!(!state.dependencies_updated);
         assert!(!state.dependencies_built);
         assert!(!state.application_built);
@@ -1,13 +1,14 @@
 use anyhow::{Context, Result};
 use colored::Colorize;
-use sha2::{Digest, Sha256};
 use std::collections::HashMap;
 use std::env;
 use std::fmt::Write;
-use std::path::{Path, PathBuf};
+use std::path::{Component, Path, PathBuf};
 use std::process::Command;
 use std::sync::OnceLock;
 
+use crate::checksum::Checksum;
+
 static VERBOSE: OnceLock<bool> = OnceLock::new();
 
 pub fn set_verbose(enabled: bool) {
@@ -147,6 +148,14 @@ pub fn get_a11y_bus_args() -> Result<Vec<String>> {
     ])
 }
 
+/// `$XDG_CACHE_HOME`, falling back to `~/.cache`.
+pub fn user_cache_dir() -> PathBuf {
+    env::var_os("XDG_CACHE_HOME").map_or_else(
+        || PathBuf::from(env::var("HOME").unwrap_or_default()).join(".cache"),
+        PathBuf::from,
+    )
+}
+
 fn path_exists(path: &str) -> bool {
     Path::new(path).exists()
 }
@@ -241,81 +250,57 @@ pub fn path_to_str(path: &Path) -> Result<&str> {
         .context("Path contains invalid UTF-8 characters")
 }
 
-pub fn download_file(url: &str, dest: &Path) -> Result<()> {
-    let response = ureq::get(url)
-        .call()
-        .map_err(|error| anyhow::anyhow!("Failed to download {url}: {error}"))?;
-    let mut file = std::fs::File::create(dest)?;
-    std::io::copy(&mut response.into_body().into_reader(), &mut file)?;
-    Ok(())
-}
-
-pub fn verify_sha256_hex(path: &Path, expected: &str) -> Result<()> {
-    let content = std::fs::read(path)?;
-    let mut hasher = Sha256::new();
-    hasher.update(&content);
-    let result = hasher.finalize();
-    let mut hash = String::with_capacity(64);
-    for byte in result {
-        write!(&mut hash, "{byte:02x}")?;
+/// Joins a relative path from a manifest onto `base`, refusing absolute paths
+/// and `..` components that would escape it, as well as symlinks already in
+/// `base` that lead out of it.
+pub fn safe_join(base: &Path, relative: &str) -> Result<PathBuf> {
+    let relative_path = Path::new(relative);
+    if relative_path
+        .components()
+        .any(|component| !matches!(component, Component::Normal(_) | Component::CurDir))
+    {
+        anyhow::bail!("Path '{relative}' must stay inside {}", base.display());
     }
-    if hash != expected {
-        return Err(anyhow::anyhow!(
-            "SHA256 mismatch for {}: expected {expected}, got {hash}",
-            path.display()
-        ));
+    let joined = base.join(relative_path);
+    // Sources staged earlier can leave symlinks behind that later ones would
+    // be written through.
+    if let Ok(canonical_base) = base.canonicalize()
+        && let Some(existing) = joined
+            .ancestors()
+            .take_while(|ancestor| ancestor.starts_with(base) && *ancestor != base)
+            .find(|ancestor| ancestor.symlink_metadata().is_ok())
+        && !existing
+            .canonicalize()
+            .is_ok_and(|existing| existing.starts_with(&canonical_base))
+    {
+        anyhow::bail!(
+            "Path '{relative}' leads outside of {} through a symlink",
+            base.display()
+        );
     }
-    Ok(())
+    Ok(joined)
 }
 
-pub fn extract_archive(
-    path: &Path,
-    archive_type: &str,
-    dest: &Path,
-    strip_components: usize,
-) -> Result<()> {
-    let temp_dir = tempfile::tempdir()?;
-    let file = std::fs::File::open(path)?;
-
-    match archive_type {
-        "tar" => tar::Archive::new(file).unpack(&temp_dir)?,
-        "tar-gzip" => {
-            let decoder = flate2::read::GzDecoder::new(file);
-            tar::Archive::new(decoder).unpack(&temp_dir)?;
-        }
-        "tar-xz" => {
-            let decoder = xz2::read::XzDecoder::new(file);
-            tar::Archive::new(decoder).unpack(&temp_dir)?;
-        }
-        "zip" => {
-            let mut archive = zip::ZipArchive::new(file)?;
-            archive.extract(&temp_dir)?;
-        }
-        other => {
-            return Err(anyhow::anyhow!("Unsupported archive type: {other}"));
-        }
-    }
-
-    std::fs::create_dir_all(dest)?;
-    for entry in walkdir::WalkDir::new(&temp_dir).min_depth(1) {
-        let entry = entry?;
-        let rel = entry.path().strip_prefix(&temp_dir)?;
-        let components: Vec<_> = rel.components().collect();
-        if components.len() <= strip_components {
-            continue;
-        }
-        let stripped: PathBuf = components[strip_components..].iter().collect();
-        let dest_path = dest.join(&stripped);
-        if entry.file_type().is_dir() {
-            std::fs::create_dir_all(&dest_path)?;
-        } else {
-            if let Some(parent) = dest_path.parent() {
-                std::fs::create_dir_all(parent)?;
-            }
-            std::fs::rename(entry.path(), &dest_path)?;
-        }
-    }
+pub fn verify_checksum(path: &Path, expected: &Checksum) -> Result<()> {
+    let mut file = std::fs::File::open(path)?;
+    let mut hasher = expected.hasher();
+    hasher.copy(&mut file, &mut std::io::sink())?;
+    expected.check(&hasher.finalize_hex(), path.display())
+}
 
+/// Copies a file while hashing it, only putting it at `dest` once it matches
+/// the expected checksum.
+pub fn copy_verified(source: &Path, dest: &Path, expected: &Checksum) -> Result<()> {
+    let dest_dir = dest.parent().context("Copy destination has no parent")?;
+    let mut temp_file = tempfile::NamedTempFile::new_in(dest_dir)?;
+    let mut hasher = expected.hasher();
+    let mut file = std::fs::File::open(source)?;
+    hasher.copy(&mut file, temp_file.as_file_mut())?;
+    expected.check(&hasher.finalize_hex(), source.display())?;
+    std::fs::set_permissions(temp_file.path(), file.metadata()?.permissions())?;
+    temp_file
+        .persist(dest)
+        .with_context(|| format!("Failed to write {}", dest.display()))?;
     Ok(())
 }
 
@@ -325,20 +310,40 @@ fn ends_with_ignore_case(filename: &str, suffix: &str) -> bool {
     f_len >= s_len && filename[f_len - s_len..].eq_ignore_ascii_case(suffix)
 }
 
-pub fn guess_archive_type(url_or_path: &str) -> String {
-    if ends_with_ignore_case(url_or_path, ".tar.gz") || ends_with_ignore_case(url_or_path, ".tgz") {
-        "tar-gzip".into()
-    } else if ends_with_ignore_case(url_or_path, ".tar.xz")
-        || ends_with_ignore_case(url_or_path, ".txz")
-    {
-        "tar-xz".into()
-    } else if ends_with_ignore_case(url_or_path, ".zip") {
-        "zip".into()
-    } else if ends_with_ignore_case(url_or_path, ".tar") {
-        "tar".into()
-    } else {
-        "tar-gzip".into()
-    }
+// Same suffixes as flatpak-builder.
+const ARCHIVE_SUFFIXES: &[(&str, &str)] = &[
+    (".tar", "tar"),
+    (".tar.gz", "tar-gzip"),
+    (".tgz", "tar-gzip"),
+    (".taz", "tar-gzip"),
+    (".tar.bz2", "tar-bzip2"),
+    (".tbz", "tar-bzip2"),
+    (".tbz2", "tar-bzip2"),
+    (".tz2", "tar-bzip2"),
+    (".tar.lz", "tar-lzip"),
+    (".tar.lzma", "tar-lzma"),
+    (".tlz", "tar-lzma"),
+    (".tar.xz", "tar-xz"),
+    (".txz", "tar-xz"),
+    (".tar.zst", "tar-zst"),
+    (".tar.zstd", "tar-zst"),
+    (".zip", "zip"),
+    (".rpm", "rpm"),
+    (".7z", "7z"),
+];
+
+pub fn guess_archive_type(url_or_path: &str) -> Result<&'static str> {
+    // Ignores query strings and fragments in URLs.
+    let name = url_or_path.split(['?', '#']).next().unwrap_or(url_or_path);
+    ARCHIVE_SUFFIXES
+        .iter()
+        .find(|(suffix, _)| ends_with_ignore_case(name, suffix))
+        .map(|(_, archive_type)| *archive_type)
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "Cannot tell the archive type of {url_or_path}, please set archive-type"
+            )
+        })
 }
 
 pub fn version_less_than(left: &str, right: &str) -> bool {
@@ -364,6 +369,9 @@ pub fn version_less_than(left: &str, right: &str) -> bool {
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::archive::extract_archive;
+    use crate::checksum::Algorithm;
+    use crate::download::download_file;
     use mockito::Server;
 
     #[test]
@@ -414,17 +422,27 @@ mod tests {
     #[test]
     fn test_guess_archive_type() {
         assert_eq!(
-            guess_archive_type("https://example.com/pkg.tar.gz"),
+            guess_archive_type("https://example.com/pkg.tar.gz").unwrap(),
             "tar-gzip"
         );
-        assert_eq!(guess_archive_type("pkg.tgz"), "tar-gzip");
-        assert_eq!(guess_archive_type("pkg.TGZ"), "tar-gzip");
-        assert_eq!(guess_archive_type("pkg.tar.xz"), "tar-xz");
-        assert_eq!(guess_archive_type("pkg.txz"), "tar-xz");
-        assert_eq!(guess_archive_type("pkg.zip"), "zip");
-        assert_eq!(guess_archive_type("pkg.tar"), "tar");
-        assert_eq!(guess_archive_type("pkg.unknown"), "tar-gzip");
-        assert_eq!(guess_archive_type("noextension"), "tar-gzip");
+        assert_eq!(guess_archive_type("pkg.tgz").unwrap(), "tar-gzip");
+        assert_eq!(guess_archive_type("pkg.TGZ").unwrap(), "tar-gzip");
+        assert_eq!(guess_archive_type("pkg.tar.xz").unwrap(), "tar-xz");
+        assert_eq!(guess_archive_type("pkg.txz").unwrap(), "tar-xz");
+        assert_eq!(guess_archive_type("pkg.zip").unwrap(), "zip");
+        assert_eq!(guess_archive_type("pkg.tar").unwrap(), "tar");
+        assert_eq!(guess_archive_type("pkg.tar.bz2").unwrap(), "tar-bzip2");
+        assert_eq!(guess_archive_type("pkg.tar.zst").unwrap(), "tar-zst");
+        assert_eq!(guess_archive_type("pkg.tar.lz").unwrap(), "tar-lzip");
+        assert_eq!(guess_archive_type("pkg.tar.lzma").unwrap(), "tar-lzma");
+        assert_eq!(guess_archive_type("pkg.rpm").unwrap(), "rpm");
+        assert_eq!(guess_archive_type("pkg.7z").unwrap(), "7z");
+        assert_eq!(
+            guess_archive_type("https://example.com/pkg.tar.bz2?download=1").unwrap(),
+            "tar-bzip2"
+        );
+        assert!(guess_archive_type("pkg.unknown").is_err());
+        assert!(guess_archive_type("noextension").is_err());
     }
 
     #[test]
@@ -443,25 +461,84 @@ mod tests {
     }
 
     #[test]
-    fn test_verify_sha256_hex_valid() {
+    fn test_verify_checksum_valid() {
         let dir = tempfile::tempdir().unwrap();
         let path = dir.path().join("test.bin");
         std::fs::write(&path, b"hello world").unwrap();
         let expected = "b94d27b9934d3e08a52e52d7da7dabfac484efe37a5380ee9088f7ace2efcde9";
-        assert!(verify_sha256_hex(&path, expected).is_ok());
+        let checksum = Checksum::new(Algorithm::Sha256, expected).unwrap();
+        assert!(verify_checksum(&path, &checksum).is_ok());
+        let checksum = Checksum::new(Algorithm::Md5, "5eb63bbbe01eeed093cb22bb8f5acdc3").unwrap();
+        assert!(verify_checksum(&path, &checksum).is_ok());
     }
 
     #[test]
-    fn test_verify_sha256_hex_mismatch() {
+    fn test_verify_checksum_mismatch() {
         let dir = tempfile::tempdir().unwrap();
         let path = dir.path().join("test.bin");
         std::fs::write(&path, b"hello world").unwrap();
-        assert!(
-            verify_sha256_hex(
-                &path,
-                "0000000000000000000000000000000000000000000000000000000000000000"
-            )
-            .is_err()
+        let checksum = Checksum::new(
+            Algorithm::Sha256,
+            "0000000000000000000000000000000000000000000000000000000000000000",
+        )
+        .unwrap();
+        assert!(verify_checksum(&path, &checksum).is_err());
+        let checksum = Checksum::new(Algorithm::Sha512, &"0".repeat(128)).unwrap();
+        assert!(verify_checksum(&path, &checksum).is_err());
+    }
+
+    #[test]
+    fn test_copy_verified() {
+        let dir = tempfile::tempdir().unwrap();
+        let source = dir.path().join("source.bin");
+        std::fs::write(&source, b"hello world").unwrap();
+        let dest = dir.path().join("dest.bin");
+
+        let wrong = Checksum::new(Algorithm::Sha1, &"0".repeat(40)).unwrap();
+        assert!(copy_verified(&source, &dest, &wrong).is_err());
+        assert!(!dest.exists());
+        assert_eq!(std::fs::read_dir(dir.path()).unwrap().count(), 1);
+
+        let right =
+            Checksum::new(Algorithm::Sha1, "2aae6c35c94fcfb415dbe95f408b9ce91ee846ed").unwrap();
+        copy_verified(&source, &dest, &right).unwrap();
+        assert_eq!(std::fs::read(&dest).unwrap(), b"hello world");
+    }
+
+    #[test]
+    fn test_safe_join() {
+        let base = Path::new("/project/.flatplay/app");
+        assert_eq!(
+            safe_join(base, "subprojects/foo").unwrap(),
+            base.join("subprojects/foo")
+        );
+        assert_eq!(safe_join(base, "./data").unwrap(), base.join("data"));
+        assert!(safe_join(base, "../escape").is_err());
+        assert!(safe_join(base, "a/../../escape").is_err());
+        assert!(safe_join(base, "/etc").is_err());
+    }
+
+    #[test]
+    fn test_safe_join_refuses_escaping_symlinks() {
+        let dir = tempfile::tempdir().unwrap();
+        let base = dir.path().join("app");
+        let outside = dir.path().join("outside");
+        std::fs::create_dir_all(base.join("data")).unwrap();
+        std::fs::create_dir_all(&outside).unwrap();
+        std::os::unix::fs::symlink(&outside, base.join("sub")).unwrap();
+        std::os::unix::fs::symlink(outside.join("missing"), base.join("dangling")).unwrap();
+        std::os::unix::fs::symlink("data", base.join("internal")).unwrap();
+
+        assert!(safe_join(&base, "sub").is_err());
+        assert!(safe_join(&base, "sub/file.txt").is_err());
+        assert!(safe_join(&base, "dangling").is_err());
+        assert_eq!(
+            safe_join(&base, "internal/file.txt").unwrap(),
+            base.join("internal/file.txt")
+        );
+        assert_eq!(
+            safe_join(&base, "new/file.txt").unwrap(),
+            base.join("new/file.txt")
         );
     }
 
@@ -493,32 +570,19 @@ mod tests {
 
         let url = format!("{}/test.zip", server.url());
 
-        download_file(&url, &archive_path).expect("Download failed");
+        download_file(&[&url], &archive_path, None).expect("Download failed");
         assert!(archive_path.exists());
 
-        verify_sha256_hex(
-            &archive_path,
+        let checksum = Checksum::new(
+            Algorithm::Sha256,
             "8739c76e681f900923b900c9df0ef75cf421d39cabb54650c4b9ad19b6a76d85",
         )
-        .expect("SHA256 mismatch");
+        .unwrap();
+        verify_checksum(&archive_path, &checksum).expect("SHA256 mismatch");
 
         extract_archive(&archive_path, "zip", &extract_dir, 0).expect("Extraction failed");
         assert!(extract_dir.exists());
 
         mock.assert();
     }
-
-    #[test]
-    fn test_download_file_http_error() {
-        let dir = tempfile::tempdir().unwrap();
-        let archive_path = dir.path().join("missing.zip");
-
-        let mut server = Server::new();
-        let mock = server.mock("GET", "/missing.zip").with_status(404).create();
-
-        let url = format!("{}/missing.zip", server.url());
-
-        assert!(download_file(&url, &archive_path).is_err());
-        mock.assert();
-    }
 }
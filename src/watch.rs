@@ -0,0 +1,279 @@
+use std::path::{Path, PathBuf};
+use std::process::Child;
+use std::sync::mpsc::{Receiver, RecvTimeoutError, channel};
+use std::thread;
+use std::time::{Duration, Instant};
+
+use anyhow::{Context, Result};
+use ignore::WalkBuilder;
+use ignore::gitignore::Gitignore;
+use nix::errno::Errno;
+use nix::sys::signal::{Signal, killpg};
+use nix::unistd::Pid;
+use notify_debouncer_mini::notify::{RecommendedWatcher, RecursiveMode};
+use notify_debouncer_mini::{DebounceEventResult, Debouncer, new_debouncer};
+
+use crate::utils::{status_warn, verbose};
+
+/// How long the sources have to be left alone before a burst of saves counts
+/// as one change.
+const DEBOUNCE: Duration = Duration::from_millis(500);
+const STOP_WAIT: Duration = Duration::from_secs(5);
+const STOP_POLL: Duration = Duration::from_millis(100);
+
+/// Decides which changes in the watched source trees matter, following the
+/// `.gitignore` files in and above them.
+pub struct SourceFilter {
+    // Deepest first, as those take precedence.
+    gitignores: Vec<Gitignore>,
+    excluded: Vec<PathBuf>,
+}
+
+impl SourceFilter {
+    /// `base_dir` is the project root, whose `.gitignore` files between it and
+    /// the roots apply too. Anything below `excluded` is never relevant.
+    pub fn new(base_dir: &Path, roots: &[PathBuf], excluded: Vec<PathBuf>) -> Self {
+        let mut gitignore_files = Vec::new();
+        for root in roots {
+            gitignore_files.extend(
+                root.ancestors()
+                    .skip(1)
+                    .take_while(|dir| dir.starts_with(base_dir))
+                    .map(|dir| dir.join(".gitignore"))
+                    .filter(|file| file.is_file()),
+            );
+            let walk = WalkBuilder::new(root)
+                .hidden(false)
+                .require_git(false)
+                .filter_entry(|entry| entry.file_name() != ".git")
+                .build();
+            gitignore_files.extend(
+                walk.flatten()
+                    .filter(|entry| entry.file_name() == ".gitignore")
+                    .map(ignore::DirEntry::into_path),
+            );
+        }
+        gitignore_files.sort();
+        gitignore_files.dedup();
+        gitignore_files.sort_by_key(|file| std::cmp::Reverse(file.components().count()));
+
+        let gitignores = gitignore_files
+            .iter()
+            .map(|file| {
+                let (gitignore, error) = Gitignore::new(file);
+                if let Some(error) = error {
+                    verbose(format!("Problem in {}: {error}", file.display()));
+                }
+                gitignore
+            })
+            .collect();
+        Self {
+            gitignores,
+            excluded,
+        }
+    }
+
+    pub fn is_relevant(&self, path: &Path) -> bool {
+        if self
+            .excluded
+            .iter()
+            .any(|excluded| path.starts_with(excluded))
+            || path
+                .components()
+                .any(|component| component.as_os_str() == ".git")
+        {
+            return false;
+        }
+        let is_dir = path.is_dir();
+        for gitignore in &self.gitignores {
+            if !path.starts_with(gitignore.path()) {
+                continue;
+            }
+            let matched = gitignore.matched_path_or_any_parents(path, is_dir);
+            if matched.is_ignore() {
+                return false;
+            }
+            if matched.is_whitelist() {
+                return true;
+            }
+        }
+        true
+    }
+}
+
+/// Watches source trees for changes that a [`SourceFilter`] considers relevant,
+/// and single files for any change.
+pub struct SourceWatcher {
+    // Watching stops when the debouncer is dropped.
+    _debouncer: Debouncer<RecommendedWatcher>,
+    events: Receiver<DebounceEventResult>,
+    roots: Vec<PathBuf>,
+    files: Vec<PathBuf>,
+    filter: SourceFilter,
+}
+
+impl SourceWatcher {
+    /// All paths are expected to be canonical.
+    pub fn new(roots: &[PathBuf], files: &[PathBuf], filter: SourceFilter) -> Result<Self> {
+        let (sender, events) = channel();
+        let mut debouncer =
+            new_debouncer(DEBOUNCE, sender).context("Failed to start watching for changes")?;
+        for root in roots {
+            debouncer
+                .watcher()
+                .watch(root, RecursiveMode::Recursive)
+                .with_context(|| format!("Failed to watch {}", root.display()))?;
+        }
+        // Editors often save by replacing files, so it is their directories
+        // that are watched.
+        let mut file_dirs: Vec<&Path> = files
+            .iter()
+            .filter_map(|file| file.parent())
+            .filter(|dir| !roots.iter().any(|root| dir.starts_with(root)))
+            .collect();
+        file_dirs.sort();
+        file_dirs.dedup();
+        for dir in file_dirs {
+            debouncer
+                .watcher()
+                .watch(dir, RecursiveMode::NonRecursive)
+                .with_context(|| format!("Failed to watch {}", dir.display()))?;
+        }
+        Ok(Self {
+            _debouncer: debouncer,
+            events,
+            roots: roots.to_vec(),
+            files: files.to_vec(),
+            filter,
+        })
+    }
+
+    /// Waits up to `timeout` for changes, returning the relevant changed paths.
+    pub fn changes(&self, timeout: Duration) -> Result<Vec<PathBuf>> {
+        let events = match self.events.recv_timeout(timeout) {
+            Ok(events) => events,
+            Err(RecvTimeoutError::Timeout) => return Ok(Vec::new()),
+            Err(RecvTimeoutError::Disconnected) => {
+                anyhow::bail!("Stopped watching for changes")
+            }
+        };
+        let events = match events {
+            Ok(events) => events,
+            Err(error) => {
+                status_warn(format!("Problem watching for changes: {error}"));
+                return Ok(Vec::new());
+            }
+        };
+        let mut paths: Vec<PathBuf> = events
+            .into_iter()
+            .map(|event| event.path)
+            .filter(|path| {
+                self.files.contains(path)
+                    || (self.roots.iter().any(|root| path.starts_with(root))
+                        && self.filter.is_relevant(path))
+            })
+            .collect();
+        paths.sort();
+        paths.dedup();
+        Ok(paths)
+    }
+}
+
+/// Stops a process started in its own process group along with everything it
+/// started, killing the group if it does not exit in time.
+pub fn stop_process_group(child: &mut Child) -> Result<()> {
+    if child.try_wait()?.is_some() {
+        return Ok(());
+    }
+    let process_group = Pid::from_raw(child.id().cast_signed());
+    match killpg(process_group, Signal::SIGTERM) {
+        Ok(()) | Err(Errno::ESRCH) => {}
+        Err(error) => return Err(anyhow::anyhow!("Failed to stop the application: {error}")),
+    }
+
+    let deadline = Instant::now() + STOP_WAIT;
+    while Instant::now() < deadline {
+        if child.try_wait()?.is_some() {
+            return Ok(());
+        }
+        thread::sleep(STOP_POLL);
+    }
+    verbose("Application did not exit in time, killing it");
+    match killpg(process_group, Signal::SIGKILL) {
+        Ok(()) | Err(Errno::ESRCH) => {}
+        Err(error) => return Err(anyhow::anyhow!("Failed to kill the application: {error}")),
+    }
+    child.wait()?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    #[test]
+    fn test_source_filter() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path();
+        let root = base_dir.join("app");
+        fs::create_dir_all(root.join("src/generated")).unwrap();
+        fs::create_dir_all(root.join("build")).unwrap();
+        fs::write(base_dir.join(".gitignore"), "*.o\n").unwrap();
+        fs::write(root.join(".gitignore"), "build/\n*.swp\n").unwrap();
+        fs::write(root.join("src/.gitignore"), "!keep.o\ngenerated/\n").unwrap();
+
+        let filter = SourceFilter::new(
+            base_dir,
+            std::slice::from_ref(&root),
+            vec![base_dir.join(".flatplay")],
+        );
+        assert!(filter.is_relevant(&root.join("src/main.c")));
+        assert!(filter.is_relevant(&root.join("meson.build")));
+        assert!(!filter.is_relevant(&root.join("src/main.o")));
+        assert!(filter.is_relevant(&root.join("src/keep.o")));
+        assert!(!filter.is_relevant(&root.join("src/.main.c.swp")));
+        assert!(!filter.is_relevant(&root.join("build/main.o")));
+        assert!(!filter.is_relevant(&root.join("build/config.h")));
+        assert!(!filter.is_relevant(&root.join("src/generated/resources.c")));
+        assert!(!filter.is_relevant(&root.join(".git/index")));
+        assert!(!filter.is_relevant(&base_dir.join(".flatplay/_build/app")));
+    }
+
+    #[test]
+    fn test_source_watcher_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let base_dir = dir.path().canonicalize().unwrap();
+        let root = base_dir.join("app");
+        let manifest_dir = base_dir.join("build-aux");
+        fs::create_dir_all(&root).unwrap();
+        fs::create_dir_all(&manifest_dir).unwrap();
+        let manifest = manifest_dir.join("org.example.App.json");
+        fs::write(&manifest, "{}").unwrap();
+
+        let filter = SourceFilter::new(&base_dir, std::slice::from_ref(&root), Vec::new());
+        let watcher = SourceWatcher::new(
+            std::slice::from_ref(&root),
+            std::slice::from_ref(&manifest),
+            filter,
+        )
+        .unwrap();
+        let wait_for_changes = || {
+            let deadline = Instant::now() + Duration::from_secs(10);
+            loop {
+                let changes = watcher.changes(Duration::from_millis(100)).unwrap();
+                if !changes.is_empty() || Instant::now() > deadline {
+                    return changes;
+                }
+            }
+        };
+
+        // Other files next to the watched ones do not count.
+        fs::write(manifest_dir.join("notes.txt"), "").unwrap();
+        fs::write(&manifest, r#"{ "id": "org.example.App" }"#).unwrap();
+        assert_eq!(wait_for_changes(), [manifest]);
+
+        fs::write(root.join("main.c"), "").unwrap();
+        assert_eq!(wait_for_changes(), [root.join("main.c")]);
+    }
+}